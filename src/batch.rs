@@ -0,0 +1,139 @@
+use std::path::Path;
+
+use rayon::prelude::*;
+use serde::Deserialize;
+
+use crate::category::{completeness, ALL_CATEGORIES};
+use crate::error::{Error, Result};
+use crate::geocode::Address;
+use crate::{get_origin_coordinates, Node, ReachableAmenity};
+
+// A row of the input file, giving either an address or both coordinates.
+// Other columns are ignored.
+#[derive(Deserialize)]
+struct OriginRow {
+    #[serde(default)]
+    address: Option<String>,
+    #[serde(default)]
+    lat: Option<f64>,
+    #[serde(default)]
+    lon: Option<f64>,
+}
+
+// Each origin in the form get_origin_coordinates takes. Rows that can't be
+// read or give no origin are kept as errors, so they get a row of their own in
+// the output rather than stopping the batch.
+fn read_origins(path: &Path) -> Result<Vec<Result<String>>> {
+    let invalid = |error: csv::Error| Error::InvalidInput(format!("{}: {}", path.display(), error));
+    let mut reader = csv::Reader::from_path(path).map_err(invalid)?;
+    let origins = reader
+        .deserialize::<OriginRow>()
+        .enumerate()
+        .map(|(index, row)| {
+            let row = row.map_err(invalid)?;
+            match (row.address, row.lat, row.lon) {
+                (_, Some(lat), Some(lon)) => Ok(format!("{},{}", lat, lon)),
+                (Some(address), _, _) if !address.trim().is_empty() => Ok(address),
+                _ => Err(Error::InvalidInput(format!(
+                    "{}: row {} has neither an address nor lat and lon",
+                    path.display(),
+                    index + 1
+                ))),
+            }
+        })
+        .collect();
+    Ok(origins)
+}
+
+// The count and nearest network distance of each category, then the
+// completeness score
+fn summary_fields(amenities: &[ReachableAmenity]) -> Vec<String> {
+    let mut fields: Vec<String> = Vec::new();
+    for category in ALL_CATEGORIES.iter() {
+        let distances: Vec<f64> = amenities
+            .iter()
+            .filter(|amenity| amenity.node.category == Some(*category))
+            .map(|amenity| amenity.network_distance)
+            .collect();
+        fields.push(distances.len().to_string());
+        fields.push(
+            distances
+                .into_iter()
+                .min_by(|a, b| a.total_cmp(b))
+                .map(|distance| format!("{:.0}", distance))
+                .unwrap_or_default(),
+        );
+    }
+    let nodes: Vec<Node> = amenities
+        .iter()
+        .map(|amenity| amenity.node.clone())
+        .collect();
+    fields.push(format!("{:.2}", completeness(&nodes).2));
+    fields
+}
+
+// Searches around every origin in the input file and writes one row per
+// origin to the output file. Origins are geocoded one at a time so Nominatim
+// isn't flooded, then searched in parallel. An origin that can't be found or
+// searched gets an error instead of failing the whole batch.
+pub fn run_batch(
+    input: &Path,
+    output: &Path,
+    addresses: Option<&[Address]>,
+    search: impl Fn((f64, f64)) -> Result<Vec<ReachableAmenity>> + Sync,
+) -> Result<()> {
+    let origins = read_origins(input)?;
+    let located: Vec<Result<(f64, f64)>> = origins
+        .iter()
+        .map(|origin| match origin {
+            Ok(origin) => get_origin_coordinates(origin.clone(), addresses),
+            Err(error) => Err(Error::InvalidInput(error.to_string())),
+        })
+        .collect();
+    // origins that couldn't be read or located are reported from that error
+    let results: Vec<Result<Vec<ReachableAmenity>>> = located
+        .par_iter()
+        .map(|coordinates| match coordinates {
+            Ok(coordinates) => search(*coordinates),
+            Err(_) => Ok(Vec::new()),
+        })
+        .collect();
+
+    let write_error = |error: csv::Error| Error::Io(error.into());
+    let mut writer = csv::Writer::from_path(output).map_err(write_error)?;
+    let mut header: Vec<String> = vec!["origin".to_string(), "lat".to_string(), "lon".to_string()];
+    for category in ALL_CATEGORIES.iter() {
+        let name = format!("{:?}", category).to_lowercase();
+        header.push(format!("{}_count", name));
+        header.push(format!("{}_nearest_m", name));
+    }
+    header.push("completeness".to_string());
+    header.push("error".to_string());
+    writer.write_record(&header).map_err(write_error)?;
+    let mut failed = 0;
+    for ((origin, coordinates), result) in origins.iter().zip(located.iter()).zip(results.iter()) {
+        let mut record: Vec<String> = vec![origin.as_deref().unwrap_or("").to_string()];
+        match (coordinates, result) {
+            (Ok(coordinates), Ok(amenities)) => {
+                record.push(coordinates.0.to_string());
+                record.push(coordinates.1.to_string());
+                record.extend(summary_fields(amenities));
+                record.push(String::new());
+            }
+            (Err(error), _) | (_, Err(error)) => {
+                failed += 1;
+                record.resize(header.len() - 1, String::new());
+                record.push(error.to_string());
+            }
+        }
+        writer.write_record(&record).map_err(write_error)?;
+    }
+    writer.flush()?;
+    println!(
+        "Searched {} origins, {} failed, results written to {}",
+        origins.len(),
+        failed,
+        output.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,315 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::category::Category;
+use crate::error::{Error, Result};
+use crate::geocode::Address;
+use crate::{BoundingBox, Node, Way};
+
+// Every cache file starts with the magic bytes, the format version and
+// whether the rest is zstd compressed. Bump the version whenever the stored
+// layout changes so older files are rebuilt instead of misread.
+const CACHE_MAGIC: &[u8; 4] = b"FMPC";
+pub const CACHE_VERSION: u32 = 1;
+const ZSTD_LEVEL: i32 = 3;
+
+pub const MAP_FILE: &str = "map.bin";
+pub const ADDRESSES_FILE: &str = "addresses.bin";
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+// What a cache covers and how it was fetched, so a cache can be found by
+// location rather than only by its folder name
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub city: String,
+    pub radius: String,
+    pub centre: (f64, f64),
+    // (south, west, north, east)
+    pub bounding_box: BoundingBox,
+    // Overpass' timestamp_osm_base, the time of the data the cache holds
+    pub osm_base: Option<String>,
+    // Hash of the query with the bounding box left out, which changes when
+    // the selected tags do
+    pub query_hash: String,
+    pub format_version: u32,
+}
+
+// bincode can't skip fields the way Node's serde attributes do, so nodes are
+// stored as plain tuples of (id, name, coordinate, category, tags)
+type StoredNode = (
+    usize,
+    Option<String>,
+    (u64, u64),
+    Option<Category>,
+    BTreeMap<String, String>,
+);
+
+// (id, node ids, tags)
+type StoredWay = (usize, Vec<usize>, BTreeMap<String, String>);
+
+// Highway nodes are stored once and referenced by id from the ways
+#[derive(Serialize, Deserialize)]
+struct StoredMap {
+    amenities: Vec<StoredNode>,
+    highway_nodes: Vec<StoredNode>,
+    highways: Vec<StoredWay>,
+}
+
+fn store_node(node: &Node) -> StoredNode {
+    (
+        node.id,
+        node.name.clone(),
+        node.coordinate,
+        node.category,
+        node.tags.clone(),
+    )
+}
+
+fn load_node((id, name, coordinate, category, tags): StoredNode) -> Node {
+    Node {
+        name,
+        coordinate,
+        id,
+        category,
+        tags,
+    }
+}
+
+fn write_cache_file<T: Serialize>(path: &Path, value: &T, compress: bool) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(CACHE_MAGIC)?;
+    writer.write_all(&CACHE_VERSION.to_le_bytes())?;
+    writer.write_all(&[compress as u8])?;
+    if compress {
+        let mut encoder = zstd::Encoder::new(writer, ZSTD_LEVEL)?;
+        bincode::serialize_into(&mut encoder, value)?;
+        encoder.finish()?.flush()?;
+    } else {
+        bincode::serialize_into(&mut writer, value)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+// None if the file is missing, from another version or corrupt
+fn read_cache_file<T: DeserializeOwned>(path: &Path) -> Option<T> {
+    let mut reader = BufReader::new(File::open(path).ok()?);
+    let mut header = [0u8; 9];
+    reader.read_exact(&mut header).ok()?;
+    if &header[0..4] != CACHE_MAGIC {
+        return None;
+    }
+    let version = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    if version != CACHE_VERSION {
+        println!(
+            "Ignoring {} written in cache format {}, expected {}",
+            path.display(),
+            version,
+            CACHE_VERSION
+        );
+        return None;
+    }
+    if header[8] == 1 {
+        bincode::deserialize_from(zstd::Decoder::new(reader).ok()?).ok()
+    } else {
+        bincode::deserialize_from(reader).ok()
+    }
+}
+
+pub fn write_binary_cache(
+    directory: &Path,
+    amenities: &[Node],
+    highways: &[Way],
+    addresses: &[Address],
+    compress: bool,
+) -> Result<()> {
+    let mut highway_nodes: HashMap<usize, StoredNode> = HashMap::new();
+    for highway in highways.iter() {
+        for node in highway.nodes.iter() {
+            highway_nodes
+                .entry(node.id)
+                .or_insert_with(|| store_node(node));
+        }
+    }
+    let map = StoredMap {
+        amenities: amenities.iter().map(store_node).collect(),
+        highway_nodes: highway_nodes.into_values().collect(),
+        highways: highways
+            .iter()
+            .map(|highway| {
+                (
+                    highway.id,
+                    highway.nodes.iter().map(|node| node.id).collect(),
+                    highway.tags.clone(),
+                )
+            })
+            .collect(),
+    };
+    write_cache_file(&directory.join(MAP_FILE), &map, compress)?;
+    write_cache_file(&directory.join(ADDRESSES_FILE), &addresses, compress)
+}
+
+pub fn read_binary_cache(directory: &Path) -> Option<(Vec<Node>, Vec<Way>)> {
+    let map: StoredMap = read_cache_file(&directory.join(MAP_FILE))?;
+    let highway_nodes: HashMap<usize, Node> = map
+        .highway_nodes
+        .into_iter()
+        .map(|stored| (stored.0, load_node(stored)))
+        .collect();
+    let amenities: Vec<Node> = map.amenities.into_iter().map(load_node).collect();
+    let highways: Vec<Way> = map
+        .highways
+        .into_iter()
+        .map(|(id, node_ids, tags)| Way {
+            id,
+            nodes: node_ids
+                .iter()
+                .filter_map(|node_id| highway_nodes.get(node_id).cloned())
+                .collect(),
+            tags,
+        })
+        .collect();
+    Some((amenities, highways))
+}
+
+pub fn read_binary_addresses(directory: &Path) -> Option<Vec<Address>> {
+    read_cache_file(&directory.join(ADDRESSES_FILE))
+}
+
+// The pretty-printed JSON files caches were written as before the binary
+// format. Caches from before the address index have no addresses.json.
+pub fn read_json_cache(directory: &Path) -> Option<(Vec<Node>, Vec<Way>)> {
+    let amenities_path = File::open(directory.join("amenities.json")).ok()?;
+    let highways_path = File::open(directory.join("highways.json")).ok()?;
+    let amenities: Vec<Node> = serde_json::from_reader(BufReader::new(amenities_path)).ok()?;
+    let highways: Vec<Way> = serde_json::from_reader(BufReader::new(highways_path)).ok()?;
+    Some((amenities, highways))
+}
+
+pub fn read_json_addresses(directory: &Path) -> Option<Vec<Address>> {
+    let addresses_path = File::open(directory.join("addresses.json")).ok()?;
+    serde_json::from_reader(BufReader::new(addresses_path)).ok()
+}
+
+pub fn cache_exists(directory: &Path) -> bool {
+    directory.join(MAP_FILE).exists() || directory.join("amenities.json").exists()
+}
+
+// Rewrites a JSON cache directory in the binary format, removing the JSON
+// files once the binary cache reads back
+pub fn convert_json_cache(directory: &Path, compress: bool) -> Result<()> {
+    let (amenities, highways) = read_json_cache(directory)
+        .ok_or_else(|| Error::CacheMissing(directory.display().to_string()))?;
+    let addresses = read_json_addresses(directory).unwrap_or_default();
+    write_binary_cache(directory, &amenities, &highways, &addresses, compress)?;
+    if read_binary_cache(directory).is_none() {
+        println!("Could not read back the converted cache, keeping the JSON files");
+        return Err(Error::CacheCorrupt(directory.display().to_string()));
+    }
+    for name in [
+        "amenities.json",
+        "highways.json",
+        "highway_nodes.json",
+        "addresses.json",
+    ] {
+        let _ = fs::remove_file(directory.join(name));
+    }
+    println!(
+        "Converted {} with {} amenities and {} ways",
+        directory.display(),
+        amenities.len(),
+        highways.len()
+    );
+    Ok(())
+}
+
+// 64 bit FNV-1a, which unlike the standard library's hasher stays the same
+// between Rust releases
+pub fn query_hash(query: &str) -> String {
+    let hash = query.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{:016x}", hash)
+}
+
+pub fn write_manifest(directory: &Path, manifest: &Manifest) -> Result<()> {
+    let mut manifest_path = File::create(directory.join(MANIFEST_FILE))?;
+    write!(
+        &mut manifest_path,
+        "{}",
+        serde_json::to_string_pretty(manifest).map_err(io::Error::from)?
+    )?;
+    Ok(())
+}
+
+pub fn read_manifest(directory: &Path) -> Option<Manifest> {
+    let manifest_path = File::open(directory.join(MANIFEST_FILE)).ok()?;
+    serde_json::from_reader(BufReader::new(manifest_path)).ok()
+}
+
+// Every cache under the directory that has a manifest
+pub fn list_manifests(cache_root: &Path) -> Vec<(PathBuf, Manifest)> {
+    let mut manifests: Vec<(PathBuf, Manifest)> = match fs::read_dir(cache_root) {
+        Ok(entries) => entries
+            .flatten()
+            // folders starting with a dot are refreshes in progress
+            .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+            .filter_map(|entry| {
+                let manifest = read_manifest(&entry.path())?;
+                Some((entry.path(), manifest))
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    manifests.sort_by(|a, b| a.0.cmp(&b.0));
+    manifests
+}
+
+fn area(bounding_box: BoundingBox) -> f64 {
+    (bounding_box.2 - bounding_box.0).max(0.0) * (bounding_box.3 - bounding_box.1).max(0.0)
+}
+
+fn intersection(a: BoundingBox, b: BoundingBox) -> BoundingBox {
+    (a.0.max(b.0), a.1.max(b.1), a.2.min(b.2), a.3.min(b.3))
+}
+
+pub fn covers(outer: BoundingBox, inner: BoundingBox) -> bool {
+    outer.0 <= inner.0 && outer.1 <= inner.1 && outer.2 >= inner.2 && outer.3 >= inner.3
+}
+
+// The smallest cache covering the whole search area, or failing that the
+// cache containing the origin that covers the most of it. The flag says
+// whether the search area is fully covered.
+pub fn find_covering_cache(
+    cache_root: &Path,
+    origin: (f64, f64),
+    search_box: BoundingBox,
+) -> Option<(Manifest, bool)> {
+    let manifests = list_manifests(cache_root);
+    let covering = manifests
+        .iter()
+        .filter(|(_, manifest)| covers(manifest.bounding_box, search_box))
+        .min_by(|a, b| area(a.1.bounding_box).total_cmp(&area(b.1.bounding_box)));
+    if let Some((_, manifest)) = covering {
+        return Some((manifest.clone(), true));
+    }
+    manifests
+        .iter()
+        .filter(|(_, manifest)| {
+            covers(
+                manifest.bounding_box,
+                (origin.0, origin.1, origin.0, origin.1),
+            )
+        })
+        .max_by(|a, b| {
+            area(intersection(a.1.bounding_box, search_box))
+                .total_cmp(&area(intersection(b.1.bounding_box, search_box)))
+        })
+        .map(|(_, manifest)| (manifest.clone(), false))
+}
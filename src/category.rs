@@ -0,0 +1,205 @@
+use std::collections::{BTreeSet, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::Node;
+
+// 15-minute-city categories an amenity can fall into
+#[derive(Eq, Hash, PartialEq, PartialOrd, Ord, Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum Category {
+    Groceries,
+    Healthcare,
+    Education,
+    Recreation,
+    Transit,
+    Culture,
+    Services,
+    Dining,
+    Shopping,
+    Other,
+}
+
+pub const ALL_CATEGORIES: [Category; 10] = [
+    Category::Groceries,
+    Category::Healthcare,
+    Category::Education,
+    Category::Recreation,
+    Category::Transit,
+    Category::Culture,
+    Category::Services,
+    Category::Dining,
+    Category::Shopping,
+    Category::Other,
+];
+
+// The categories a neighbourhood needs within reach to count as complete
+pub const ESSENTIAL_CATEGORIES: [Category; 7] = [
+    Category::Groceries,
+    Category::Healthcare,
+    Category::Education,
+    Category::Recreation,
+    Category::Transit,
+    Category::Culture,
+    Category::Services,
+];
+
+fn amenity_category(amenity: &str) -> Category {
+    match amenity {
+        "marketplace" => Category::Groceries,
+        "pharmacy" | "hospital" | "clinic" | "doctors" | "dentist" | "health_centre" => {
+            Category::Healthcare
+        }
+        "school" | "kindergarten" | "childcare" | "college" | "university" => Category::Education,
+        "bus_station" | "ferry_terminal" | "bicycle_rental" | "car_sharing" | "taxi" => {
+            Category::Transit
+        }
+        "library" | "theatre" | "cinema" | "arts_centre" | "community_centre"
+        | "place_of_worship" => Category::Culture,
+        "post_office" | "bank" | "atm" | "townhall" | "police" | "fire_station" => {
+            Category::Services
+        }
+        "restaurant" | "cafe" | "pub" | "bar" | "fast_food" | "food_court" | "ice_cream" => {
+            Category::Dining
+        }
+        _ => Category::Other,
+    }
+}
+
+fn shop_category(shop: &str) -> Category {
+    match shop {
+        "supermarket" | "convenience" | "greengrocer" | "bakery" | "butcher" | "grocery"
+        | "deli" | "farm" | "seafood" | "health_food" => Category::Groceries,
+        "chemist" | "medical_supply" | "optician" | "hearing_aids" => Category::Healthcare,
+        _ => Category::Shopping,
+    }
+}
+
+// Classifies an element by its OSM tags. Returns None for elements that are
+// not points of interest at all, such as plain footways.
+pub fn categorise(tags: &HashMap<String, String>) -> Option<Category> {
+    if let Some(amenity) = tags.get("amenity") {
+        return Some(amenity_category(amenity));
+    }
+    if let Some(shop) = tags.get("shop") {
+        return Some(shop_category(shop));
+    }
+    if tags.contains_key("leisure") {
+        return Some(Category::Recreation);
+    }
+    if tags.contains_key("public_transport")
+        || tags.get("highway").map(|highway| highway.as_str()) == Some("bus_stop")
+        || tags.get("railway").map(|railway| railway.as_str()) == Some("station")
+    {
+        return Some(Category::Transit);
+    }
+    if let Some(tourism) = tags.get("tourism") {
+        if tourism == "museum" || tourism == "gallery" {
+            return Some(Category::Culture);
+        }
+    }
+    None
+}
+
+// The tag that gave the element its category, whose value names amenities
+// without a name tag, such as "pharmacy" or "bus_stop"
+pub fn category_key(tags: &HashMap<String, String>) -> Option<&'static str> {
+    categorise(tags)?;
+    ["amenity", "shop", "leisure", "public_transport"]
+        .into_iter()
+        .find(|key| tags.contains_key(*key))
+        .or_else(
+            || match tags.get("highway").map(|highway| highway.as_str()) {
+                Some("bus_stop") => Some("highway"),
+                _ => None,
+            },
+        )
+        .or_else(
+            || match tags.get("railway").map(|railway| railway.as_str()) {
+                Some("station") => Some("railway"),
+                _ => None,
+            },
+        )
+        .or_else(|| tags.contains_key("tourism").then_some("tourism"))
+}
+
+// The essential categories reached by the amenities, the ones missing, and the
+// share of essential categories reached between 0 and 1
+pub fn completeness(amenities: &[Node]) -> (Vec<Category>, Vec<Category>, f64) {
+    let present: BTreeSet<Category> = amenities
+        .iter()
+        .filter_map(|amenity| amenity.category)
+        .collect();
+    let (reached, missing): (Vec<Category>, Vec<Category>) = ESSENTIAL_CATEGORIES
+        .iter()
+        .partition(|category| present.contains(category));
+    let score = reached.len() as f64 / ESSENTIAL_CATEGORIES.len() as f64;
+    (reached, missing, score)
+}
+
+pub fn print_completeness_report(amenities: &[Node]) {
+    let (reached, missing, score) = completeness(amenities);
+    println!("Reachable essential categories: {:?}", reached);
+    println!("Missing essential categories: {:?}", missing);
+    println!("15-minute completeness score: {:.0}%", score * 100.0);
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::response_to_structures;
+
+    fn tags(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn finds_the_tag_that_gave_the_category() {
+        let cases = [
+            (tags(&[("amenity", "pharmacy")]), Some("amenity")),
+            (tags(&[("shop", "bakery")]), Some("shop")),
+            (tags(&[("highway", "bus_stop")]), Some("highway")),
+            (tags(&[("railway", "station")]), Some("railway")),
+            (
+                tags(&[("public_transport", "platform")]),
+                Some("public_transport"),
+            ),
+            (tags(&[("tourism", "museum")]), Some("tourism")),
+            (tags(&[("tourism", "hotel")]), None),
+            (tags(&[("highway", "footway")]), None),
+        ];
+        for (tags, key) in cases.iter() {
+            assert_eq!(category_key(tags), *key, "{:?}", tags);
+        }
+    }
+
+    #[test]
+    fn keeps_unnamed_amenities() {
+        let response = json!({"elements": [
+            {"type": "node", "id": 1, "lat": 55.95, "lon": -3.19,
+                "tags": {"amenity": "pharmacy"}},
+            {"type": "node", "id": 2, "lat": 55.95, "lon": -3.19,
+                "tags": {"highway": "bus_stop"}},
+            {"type": "node", "id": 3, "lat": 55.95, "lon": -3.19,
+                "tags": {"railway": "station", "name": "Waverley"}},
+        ]});
+        let (amenities, _, _, _) = response_to_structures(response).unwrap();
+        let mut names: Vec<(Option<String>, Option<Category>)> = amenities
+            .into_iter()
+            .map(|amenity| (amenity.name, amenity.category))
+            .collect();
+        names.sort_by_key(|(name, _)| name.clone());
+        assert_eq!(
+            names,
+            vec![
+                (Some("\"Waverley\"".to_string()), Some(Category::Transit)),
+                (Some("\"bus_stop\"".to_string()), Some(Category::Transit)),
+                (Some("\"pharmacy\"".to_string()), Some(Category::Healthcare)),
+            ]
+        );
+    }
+}
@@ -0,0 +1,349 @@
+use std::fs;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::tags::Tag;
+
+use crate::BoundingBox;
+
+// Marks a missing sample in SRTM tiles
+const HGT_VOID: f32 = -32768.0;
+
+// A grid of heights in metres, row 0 along the northern edge, with samples
+// spaced evenly in degrees
+pub struct Grid {
+    north: f64,
+    west: f64,
+    lat_step: f64,
+    lon_step: f64,
+    rows: usize,
+    columns: usize,
+    heights: Vec<f32>,
+    no_data: Option<f32>,
+}
+
+impl Grid {
+    fn south(&self) -> f64 {
+        self.north - self.lat_step * (self.rows - 1) as f64
+    }
+
+    fn east(&self) -> f64 {
+        self.west + self.lon_step * (self.columns - 1) as f64
+    }
+
+    fn overlaps(&self, bounding_box: BoundingBox) -> bool {
+        self.south() <= bounding_box.2
+            && self.north >= bounding_box.0
+            && self.west <= bounding_box.3
+            && self.east() >= bounding_box.1
+    }
+
+    fn height(&self, row: usize, column: usize) -> Option<f64> {
+        let height = self.heights[row * self.columns + column];
+        if height.is_nan() || height == HGT_VOID || Some(height) == self.no_data {
+            None
+        } else {
+            Some(height as f64)
+        }
+    }
+
+    // Bilinear interpolation between the four samples around the point
+    fn elevation(&self, lat: f64, lon: f64) -> Option<f64> {
+        let row = (self.north - lat) / self.lat_step;
+        let column = (lon - self.west) / self.lon_step;
+        // outside the grid, leaving the point to a neighbouring tile
+        if !(0.0..=(self.rows - 1) as f64).contains(&row)
+            || !(0.0..=(self.columns - 1) as f64).contains(&column)
+        {
+            return None;
+        }
+        let (top, left) = (row.floor() as usize, column.floor() as usize);
+        let bottom = (top + 1).min(self.rows - 1);
+        let right = (left + 1).min(self.columns - 1);
+        let (row_fraction, column_fraction) = (row - top as f64, column - left as f64);
+        let upper = self.height(top, left)? * (1.0 - column_fraction)
+            + self.height(top, right)? * column_fraction;
+        let lower = self.height(bottom, left)? * (1.0 - column_fraction)
+            + self.height(bottom, right)? * column_fraction;
+        Some(upper * (1.0 - row_fraction) + lower * row_fraction)
+    }
+}
+
+// South west corner of an SRTM tile from its name, such as N55W004.hgt
+fn hgt_corner(name: &str) -> Option<(f64, f64)> {
+    let name = name.to_uppercase();
+    let lat_sign = match name.get(0..1)? {
+        "N" => 1.0,
+        "S" => -1.0,
+        _ => return None,
+    };
+    let lon_sign = match name.get(3..4)? {
+        "E" => 1.0,
+        "W" => -1.0,
+        _ => return None,
+    };
+    let lat = name.get(1..3)?.parse::<f64>().ok()?;
+    let lon = name.get(4..7)?.parse::<f64>().ok()?;
+    Some((lat_sign * lat, lon_sign * lon))
+}
+
+// SRTM tiles are a square of big-endian 16 bit heights covering one degree,
+// 1201 samples a side at 3 arc seconds or 3601 at 1 arc second
+fn read_hgt(path: &Path, corner: (f64, f64)) -> Option<Grid> {
+    let bytes = fs::read(path).ok()?;
+    let size = ((bytes.len() / 2) as f64).sqrt() as usize;
+    if size < 2 || size * size * 2 != bytes.len() {
+        return None;
+    }
+    let heights: Vec<f32> = bytes
+        .chunks_exact(2)
+        .map(|pair| i16::from_be_bytes([pair[0], pair[1]]) as f32)
+        .collect();
+    Some(Grid {
+        north: corner.0 + 1.0,
+        west: corner.1,
+        lat_step: 1.0 / (size - 1) as f64,
+        lon_step: 1.0 / (size - 1) as f64,
+        rows: size,
+        columns: size,
+        heights,
+        no_data: None,
+    })
+}
+
+// Single band GeoTIFFs in WGS84 degrees, placed by their tie point and pixel
+// scale. Heights are taken at pixel centres.
+fn read_geotiff(path: &Path) -> Option<Grid> {
+    let mut decoder = Decoder::new(BufReader::new(File::open(path).ok()?)).ok()?;
+    let (columns, rows) = decoder.dimensions().ok()?;
+    let tie_point = decoder.get_tag_f64_vec(Tag::ModelTiepointTag).ok()?;
+    let scale = decoder.get_tag_f64_vec(Tag::ModelPixelScaleTag).ok()?;
+    if tie_point.len() < 6 || scale.len() < 2 {
+        return None;
+    }
+    // GDAL stores the no data value as text
+    let no_data = decoder
+        .get_tag_ascii_string(Tag::Unknown(42113))
+        .ok()
+        .and_then(|value| value.trim().trim_end_matches('\0').parse::<f32>().ok());
+    let heights: Vec<f32> = match decoder.read_image().ok()? {
+        DecodingResult::I16(values) => values.into_iter().map(|value| value as f32).collect(),
+        DecodingResult::U16(values) => values.into_iter().map(|value| value as f32).collect(),
+        DecodingResult::I32(values) => values.into_iter().map(|value| value as f32).collect(),
+        DecodingResult::F32(values) => values,
+        DecodingResult::F64(values) => values.into_iter().map(|value| value as f32).collect(),
+        _ => return None,
+    };
+    let (rows, columns) = (rows as usize, columns as usize);
+    if rows < 2 || columns < 2 || heights.len() < rows * columns {
+        return None;
+    }
+    Some(Grid {
+        north: tie_point[4] + (tie_point[1] - 0.5) * scale[1],
+        west: tie_point[3] - (tie_point[0] - 0.5) * scale[0],
+        lat_step: scale[1],
+        lon_step: scale[0],
+        rows,
+        columns,
+        heights,
+        no_data,
+    })
+}
+
+// Loads every .hgt and GeoTIFF file in the directory that overlaps the
+// bounding box. Unreadable files are skipped with a warning.
+pub fn load_dem(directory: &Path, bounding_box: BoundingBox) -> Vec<Grid> {
+    let mut grids: Vec<Grid> = Vec::new();
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(_) => {
+            println!("Could not read elevation directory {}", directory.display());
+            return grids;
+        }
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().to_string();
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let grid = match extension.as_str() {
+            "hgt" => match hgt_corner(&name) {
+                // Skip tiles outside the area before reading them
+                Some(corner)
+                    if corner.0 <= bounding_box.2
+                        && corner.0 + 1.0 >= bounding_box.0
+                        && corner.1 <= bounding_box.3
+                        && corner.1 + 1.0 >= bounding_box.1 =>
+                {
+                    read_hgt(&path, corner)
+                }
+                Some(_) => continue,
+                None => None,
+            },
+            "tif" | "tiff" => read_geotiff(&path),
+            _ => continue,
+        };
+        match grid {
+            Some(grid) if grid.overlaps(bounding_box) => grids.push(grid),
+            Some(_) => {}
+            None => println!("Skipping unreadable elevation file {}", path.display()),
+        }
+    }
+    grids
+}
+
+// Height in metres at the point from the first grid covering it
+pub fn elevation(dem: &[Grid], coordinates: (f64, f64)) -> Option<f64> {
+    dem.iter()
+        .find_map(|grid| grid.elevation(coordinates.0, coordinates.1))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+    use std::path::PathBuf;
+
+    use tiff::encoder::{colortype, TiffEncoder};
+
+    use super::*;
+    use crate::profile::{slope_factor, Profile};
+    use crate::{create_graph, create_kdtree, filter_map_data, Node, Way};
+
+    fn temp_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    // Rows run north to south: 100 200 along 56N, 300 400 along 55N
+    fn write_hgt(directory: &Path) {
+        let bytes: Vec<u8> = [100_i16, 200, 300, 400]
+            .iter()
+            .flat_map(|height| height.to_be_bytes())
+            .collect();
+        fs::write(directory.join("N55W004.hgt"), bytes).unwrap();
+    }
+
+    #[test]
+    fn reads_hgt_rows_from_the_north() {
+        let directory = temp_directory("elevation_hgt");
+        write_hgt(&directory);
+        let dem = load_dem(&directory, (55.2, -3.8, 55.8, -3.2));
+        let _ = fs::remove_dir_all(&directory);
+        assert_eq!(dem.len(), 1);
+        assert_eq!(elevation(&dem, (56.0, -4.0)), Some(100.0));
+        assert_eq!(elevation(&dem, (56.0, -3.0)), Some(200.0));
+        assert_eq!(elevation(&dem, (55.0, -4.0)), Some(300.0));
+        assert_eq!(elevation(&dem, (55.0, -3.0)), Some(400.0));
+        // just past the southern and eastern edges
+        assert_eq!(elevation(&dem, (54.5, -3.5)), None);
+        assert_eq!(elevation(&dem, (55.5, -2.5)), None);
+    }
+
+    #[test]
+    fn interpolates_between_samples() {
+        let directory = temp_directory("elevation_bilinear");
+        write_hgt(&directory);
+        let dem = load_dem(&directory, (55.2, -3.8, 55.8, -3.2));
+        let _ = fs::remove_dir_all(&directory);
+        assert_eq!(elevation(&dem, (55.5, -3.5)), Some(250.0));
+        // a quarter of the way east, halfway south
+        assert_eq!(elevation(&dem, (55.5, -3.75)), Some(225.0));
+        assert_eq!(elevation(&dem, (56.0, -3.5)), Some(150.0));
+    }
+
+    #[test]
+    fn skips_hgt_tiles_outside_the_area() {
+        let directory = temp_directory("elevation_outside");
+        write_hgt(&directory);
+        let dem = load_dem(&directory, (51.4, -0.2, 51.6, 0.0));
+        let _ = fs::remove_dir_all(&directory);
+        assert!(dem.is_empty());
+    }
+
+    #[test]
+    fn places_geotiffs_by_pixel_centre() {
+        let directory = temp_directory("elevation_geotiff");
+        let path = directory.join("dem.tif");
+        let mut encoder = TiffEncoder::new(File::create(&path).unwrap()).unwrap();
+        let mut image = encoder.new_image::<colortype::GrayI16>(2, 2).unwrap();
+        // the top left corner of the top left pixel is at 56N 4W
+        image
+            .encoder()
+            .write_tag(Tag::ModelTiepointTag, &[0.0, 0.0, 0.0, -4.0, 56.0, 0.0][..])
+            .unwrap();
+        image
+            .encoder()
+            .write_tag(Tag::ModelPixelScaleTag, &[1.0, 1.0, 0.0][..])
+            .unwrap();
+        image.write_data(&[100, 200, 300, 400]).unwrap();
+        let dem = load_dem(&directory, (55.0, -3.5, 55.5, -3.0));
+        let _ = fs::remove_dir_all(&directory);
+        assert_eq!(dem.len(), 1);
+        assert_eq!(elevation(&dem, (55.5, -3.5)), Some(100.0));
+        assert_eq!(elevation(&dem, (54.5, -2.5)), Some(400.0));
+        assert_eq!(elevation(&dem, (55.0, -3.0)), Some(250.0));
+        assert_eq!(elevation(&dem, (56.0, -4.0)), None);
+    }
+
+    #[test]
+    fn flat_ground_keeps_the_speed() {
+        assert_eq!(slope_factor(0.0), 1.0);
+        assert!(slope_factor(0.1) < slope_factor(-0.1));
+    }
+
+    fn node(id: usize, lat: f64) -> Node {
+        Node {
+            name: None,
+            coordinate: (lat.to_bits(), (-3.5_f64).to_bits()),
+            id,
+            category: None,
+            tags: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn climbing_a_way_takes_longer_than_coming_down() {
+        // 1 km north across the synthetic tile, where the ground falls from
+        // 250 m towards 56N
+        let directory = temp_directory("elevation_edges");
+        write_hgt(&directory);
+        let dem = load_dem(&directory, (55.2, -3.8, 55.8, -3.2));
+        let _ = fs::remove_dir_all(&directory);
+        let (south, north) = (node(1, 55.5), node(2, 55.509));
+        assert!(elevation(&dem, (55.5, -3.5)) > elevation(&dem, (55.509, -3.5)));
+        let way = Way {
+            id: 3,
+            nodes: vec![south, north],
+            tags: BTreeMap::from([("highway".to_string(), "footway".to_string())]),
+        };
+        let profile = Profile::from_name("adult").unwrap();
+        let (amenities, highways, highway_nodes, node_lut) =
+            filter_map_data(Vec::new(), vec![way], profile);
+        let nearest_nodes = create_kdtree(highway_nodes.clone());
+        let graph = create_graph(
+            amenities,
+            highways,
+            highway_nodes,
+            node_lut.clone(),
+            nearest_nodes,
+            profile,
+            &dem,
+        );
+        let weights: HashMap<(usize, usize), usize> = graph
+            .get_edges()
+            .iter()
+            .map(|edge| {
+                let from = *node_lut.get_by_left(&edge.from).unwrap();
+                let to = *node_lut.get_by_left(&edge.to).unwrap();
+                ((from, to), edge.weight)
+            })
+            .collect();
+        assert!(weights[&(1, 2)] < weights[&(2, 1)]);
+    }
+}
@@ -0,0 +1,118 @@
+use std::fmt;
+use std::io;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// Everything that can stop a search. Each kind has its own exit code so
+// scripts can tell them apart.
+#[derive(Debug)]
+pub enum Error {
+    // Bad arguments or input, such as an unknown profile
+    InvalidInput(String),
+    // Nominatim found nothing for the address
+    GeocodeNotFound(String),
+    // Nominatim could not be reached or returned an error
+    GeocoderUnavailable(String),
+    OverpassTimeout,
+    OverpassRateLimited,
+    // No Overpass server answered, or one answered with an error
+    OverpassUnavailable(String),
+    // Overpass answered with something that isn't the expected JSON
+    MalformedResponse(String),
+    // A local .osm or .osm.pbf file that can't be read
+    InvalidMapFile(String),
+    CacheMissing(String),
+    CacheCorrupt(String),
+    // There are no ways the profile can use near the origin
+    OriginNotSnappable((f64, f64)),
+    Io(io::Error),
+}
+
+impl Error {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::InvalidInput(_) => 2,
+            Error::GeocodeNotFound(_) => 3,
+            Error::GeocoderUnavailable(_) => 4,
+            Error::OverpassTimeout => 5,
+            Error::OverpassRateLimited => 6,
+            Error::OverpassUnavailable(_) => 7,
+            Error::MalformedResponse(_) => 8,
+            Error::InvalidMapFile(_) => 9,
+            Error::CacheMissing(_) => 10,
+            Error::CacheCorrupt(_) => 11,
+            Error::OriginNotSnappable(_) => 12,
+            Error::Io(_) => 13,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidInput(message) => write!(f, "{}", message),
+            Error::GeocodeNotFound(address) => write!(
+                f,
+                "Could not find \"{}\", check the spelling or give lat,lon coordinates instead",
+                address
+            ),
+            Error::GeocoderUnavailable(reason) => write!(
+                f,
+                "Could not reach the geocoder ({}), try again later or give lat,lon coordinates instead",
+                reason
+            ),
+            Error::OverpassTimeout => write!(
+                f,
+                "Overpass timed out, try a smaller distance or a cache"
+            ),
+            Error::OverpassRateLimited => write!(
+                f,
+                "Overpass is rate limiting requests, wait a minute and try again"
+            ),
+            Error::OverpassUnavailable(reason) => write!(
+                f,
+                "Could not get data from Overpass ({}), check your connection or search a cache",
+                reason
+            ),
+            Error::MalformedResponse(reason) => {
+                write!(f, "Overpass sent an unexpected response: {}", reason)
+            }
+            Error::InvalidMapFile(reason) => write!(f, "Could not read the map file: {}", reason),
+            Error::CacheMissing(name) => write!(
+                f,
+                "No cache at {}, build one with `cache build --city <city> --radius <metres>` or list them with `cache list`",
+                name
+            ),
+            Error::CacheCorrupt(name) => write!(
+                f,
+                "The cache at {} can't be read, rebuild it with `cache refresh`",
+                name
+            ),
+            Error::OriginNotSnappable(origin) => write!(
+                f,
+                "No usable paths near {:?} for this profile, try another origin or profile",
+                origin
+            ),
+            Error::Io(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+// bincode only fails to write when the writer does or a value can't be
+// serialised, both of which leave the file unusable
+impl From<bincode::Error> for Error {
+    fn from(error: bincode::Error) -> Self {
+        match *error {
+            bincode::ErrorKind::Io(error) => Error::Io(error),
+            other => Error::Io(io::Error::other(other)),
+        }
+    }
+}
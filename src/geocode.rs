@@ -0,0 +1,251 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use strsim::normalized_levenshtein;
+
+// How alike two street names have to be to count as the same street
+const STREET_SIMILARITY: f64 = 0.85;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Address {
+    street: Option<String>,
+    housenumber: Option<String>,
+    postcode: Option<String>,
+    city: Option<String>,
+    coordinate: (f64, f64),
+}
+
+fn tag(element: &Value, key: &str) -> Option<String> {
+    element["tags"][key].as_str().map(|value| value.to_string())
+}
+
+// Collects every node and way in an Overpass response carrying addr:* tags
+pub fn response_to_addresses(response: &Value) -> Vec<Address> {
+    let empty = Vec::new();
+    let elements = response["elements"].as_array().unwrap_or(&empty);
+    elements
+        .iter()
+        .filter_map(|element| {
+            let street = tag(element, "addr:street");
+            let housenumber = tag(element, "addr:housenumber");
+            if street.is_none() && housenumber.is_none() {
+                return None;
+            }
+            let coordinate = if element["type"] == "node" {
+                (element["lat"].as_f64()?, element["lon"].as_f64()?)
+            } else {
+                let bounds = &element["bounds"];
+                (
+                    (bounds["minlat"].as_f64()? + bounds["maxlat"].as_f64()?) / 2.0,
+                    (bounds["minlon"].as_f64()? + bounds["maxlon"].as_f64()?) / 2.0,
+                )
+            };
+            Some(Address {
+                street,
+                housenumber,
+                postcode: tag(element, "addr:postcode"),
+                city: tag(element, "addr:city"),
+                coordinate,
+            })
+        })
+        .collect()
+}
+
+// Lowercases, strips punctuation and expands the common street abbreviations,
+// so "8 Roxburgh Pl." and "8 roxburgh place" compare equal
+fn normalise(text: &str) -> Vec<String> {
+    text.to_lowercase()
+        .split(|character: char| !character.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            match word {
+                "st" => "street",
+                "rd" => "road",
+                "ave" | "av" => "avenue",
+                "pl" => "place",
+                "sq" => "square",
+                "ln" => "lane",
+                "cres" => "crescent",
+                "dr" => "drive",
+                "ter" | "terr" => "terrace",
+                "gdns" => "gardens",
+                other => other,
+            }
+            .to_string()
+        })
+        .collect()
+}
+
+// Best similarity between the street name and any run of the same number of
+// words in the query, with where that run starts
+fn street_similarity(street: &[String], query: &[String]) -> (f64, usize) {
+    if street.is_empty() || street.len() > query.len() {
+        return (0.0, 0);
+    }
+    let words = street.len();
+    let street = street.join(" ");
+    query
+        .windows(words)
+        .map(|window| normalized_levenshtein(&street, &window.join(" ")))
+        .enumerate()
+        .fold((0.0, 0), |best, (start, similarity)| {
+            if similarity > best.0 {
+                (similarity, start)
+            } else {
+                best
+            }
+        })
+}
+
+// Where the words appear next to each other, in order, in the query
+fn find_words(query: &[String], words: &[String]) -> Option<usize> {
+    if words.is_empty() {
+        return None;
+    }
+    query
+        .windows(words.len())
+        .position(|window| window == words)
+}
+
+// How well the address matches the query and whether its house number is in
+// it, or None unless every word of the query is the address' street, house
+// number, postcode or city. A query naming a town or postcode the address
+// doesn't have is then left to Nominatim rather than matched to the wrong
+// place.
+fn match_address(address: &Address, query: &[String]) -> Option<(f64, bool)> {
+    let street = normalise(address.street.as_deref().unwrap_or(""));
+    let (similarity, start) = street_similarity(&street, query);
+    if similarity < STREET_SIMILARITY {
+        return None;
+    }
+    let mut covered = vec![false; query.len()];
+    covered[start..start + street.len()].fill(true);
+    let mut score = similarity;
+    for extra in [&address.postcode, &address.city].into_iter().flatten() {
+        let words = normalise(extra);
+        if let Some(start) = find_words(query, &words) {
+            covered[start..start + words.len()].fill(true);
+            score += 0.1;
+        }
+    }
+    let housenumber = address
+        .housenumber
+        .as_deref()
+        .map(|housenumber| normalise(housenumber).join(""));
+    let number = query
+        .iter()
+        .enumerate()
+        .position(|(index, word)| !covered[index] && Some(word) == housenumber.as_ref());
+    if let Some(index) = number {
+        covered[index] = true;
+    }
+    if covered.contains(&false) {
+        return None;
+    }
+    Some((score, number.is_some()))
+}
+
+// Resolves a free-text address against the index. The street name is matched
+// fuzzily and everything else in the query exactly. With a house number the
+// best matching address is returned, without one the centre of the matching
+// stretch of street.
+pub fn find_address(addresses: &[Address], query: &str) -> Option<(f64, f64)> {
+    let query_words = normalise(query);
+    let mut best: Option<(f64, (f64, f64))> = None;
+    let mut street_matches: Vec<(f64, f64)> = Vec::new();
+    for address in addresses.iter() {
+        let (score, has_number) = match match_address(address, &query_words) {
+            Some(matched) => matched,
+            None => continue,
+        };
+        if !has_number {
+            street_matches.push(address.coordinate);
+        } else if best.is_none_or(|(best_score, _)| score > best_score) {
+            best = Some((score, address.coordinate));
+        }
+    }
+    // a house number in the query leaves out every address without it
+    if best.is_some() {
+        return best.map(|(_, coordinate)| coordinate);
+    }
+    if street_matches.is_empty() {
+        return None;
+    }
+    let count = street_matches.len() as f64;
+    let sum = street_matches.iter().fold((0.0, 0.0), |sum, coordinate| {
+        (sum.0 + coordinate.0, sum.1 + coordinate.1)
+    });
+    Some((sum.0 / count, sum.1 / count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(
+        housenumber: &str,
+        street: &str,
+        postcode: &str,
+        city: &str,
+        coordinate: (f64, f64),
+    ) -> Address {
+        Address {
+            street: Some(street.to_string()),
+            housenumber: Some(housenumber.to_string()),
+            postcode: Some(postcode.to_string()),
+            city: Some(city.to_string()),
+            coordinate,
+        }
+    }
+
+    fn index() -> Vec<Address> {
+        vec![
+            address("8", "Roxburgh Place", "EH8 9SU", "Edinburgh", (1.0, 1.0)),
+            address("9", "Roxburgh Place", "EH8 9YL", "Edinburgh", (2.0, 2.0)),
+            address("12a", "Roxburgh Place", "EH8 9YL", "Edinburgh", (3.0, 3.0)),
+            address("1", "High Street", "EH1 1SR", "Edinburgh", (4.0, 4.0)),
+        ]
+    }
+
+    #[test]
+    fn expands_abbreviations() {
+        assert_eq!(find_address(&index(), "8 Roxburgh Pl."), Some((1.0, 1.0)));
+        assert_eq!(
+            find_address(&index(), "1 High St, Edinburgh"),
+            Some((4.0, 4.0))
+        );
+    }
+
+    #[test]
+    fn matches_misspelt_streets() {
+        assert_eq!(find_address(&index(), "9 Roxburg Place"), Some((2.0, 2.0)));
+    }
+
+    #[test]
+    fn house_numbers_must_match() {
+        assert_eq!(
+            find_address(&index(), "12A Roxburgh Place"),
+            Some((3.0, 3.0))
+        );
+        assert_eq!(find_address(&index(), "10 Roxburgh Place"), None);
+    }
+
+    #[test]
+    fn postcode_halves_are_not_house_numbers() {
+        // the centre of the two addresses in EH8 9YL
+        assert_eq!(
+            find_address(&index(), "Roxburgh Place EH8 9YL"),
+            Some((2.5, 2.5))
+        );
+        assert_eq!(
+            find_address(&index(), "9 Roxburgh Place, Edinburgh EH8 9YL"),
+            Some((2.0, 2.0))
+        );
+    }
+
+    #[test]
+    fn leaves_other_places_to_the_geocoder() {
+        assert_eq!(find_address(&index(), "1 High Street, Glasgow"), None);
+        assert_eq!(find_address(&index(), "1 High St London SW1"), None);
+        assert_eq!(find_address(&index(), "8 Roxburgh Place EH1 1SR"), None);
+    }
+}
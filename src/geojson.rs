@@ -0,0 +1,62 @@
+use serde_json::{json, Value};
+
+use crate::ReachableAmenity;
+
+// Names are stored as JSON string literals, quotes and escapes included
+fn display_name(name: &Option<String>) -> Option<String> {
+    let name = name.as_ref()?;
+    Some(serde_json::from_str::<String>(name).unwrap_or(name.clone()))
+}
+
+// A FeatureCollection with a Point per amenity, carrying its name, category,
+// distances and walking time, a LineString per route when routes were asked
+// for, plus the origin of the query
+pub fn amenities_to_geojson(origin: (f64, f64), amenities: &[ReachableAmenity]) -> Value {
+    let mut features: Vec<Value> = amenities
+        .iter()
+        .map(|amenity| {
+            let node = &amenity.node;
+            json!({
+                "type": "Feature",
+                "id": node.id,
+                "geometry": {
+                    "type": "Point",
+                    "coordinates": [
+                        f64::from_bits(node.coordinate.1),
+                        f64::from_bits(node.coordinate.0),
+                    ],
+                },
+                "properties": {
+                    "name": display_name(&node.name),
+                    "category": node.category,
+                    "network_distance_m": amenity.network_distance.round(),
+                    "walking_time_s": amenity.walking_time.round(),
+                    "straight_line_distance_m": amenity.straight_line_distance.round(),
+                },
+            })
+        })
+        .collect();
+    for amenity in amenities.iter() {
+        if let Some(route) = &amenity.route {
+            let coordinates: Vec<[f64; 2]> = route.iter().map(|point| [point.1, point.0]).collect();
+            features.push(json!({
+                "type": "Feature",
+                "geometry": {"type": "LineString", "coordinates": coordinates},
+                "properties": {
+                    "name": display_name(&amenity.node.name),
+                    "amenity_id": amenity.node.id,
+                    "network_distance_m": amenity.network_distance.round(),
+                },
+            }));
+        }
+    }
+    features.push(json!({
+        "type": "Feature",
+        "geometry": {"type": "Point", "coordinates": [origin.1, origin.0]},
+        "properties": {"origin": true},
+    }));
+    json!({
+        "type": "FeatureCollection",
+        "features": features,
+    })
+}
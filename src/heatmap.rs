@@ -0,0 +1,300 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use haversine_redux::Location;
+use kiddo::{NearestNeighbour, SquaredEuclidean};
+use rayon::prelude::*;
+use serde_json::json;
+
+use crate::category::{Category, ESSENTIAL_CATEGORIES};
+use crate::elevation::Grid;
+use crate::error::{Error, Result};
+use crate::profile::{seconds_to_weight, Profile};
+use crate::search::{adjacency, bounded_search};
+use crate::{
+    create_graph, create_kdtree, filter_map_data, get_graph_id, BoundingBox, MapData, Node, Way,
+};
+
+// Metres in a degree of latitude
+const METRES_PER_DEGREE: f64 = 111000.0;
+
+// Marks cells with no walkable node nearby in the ASCII grid
+const NO_DATA: i32 = -9999;
+
+// Most cells a heatmap can have, a 1000 by 1000 grid, beyond which it would
+// take hours to search and gigabytes to hold
+const MAX_CELLS: f64 = 1_000_000.0;
+
+// A regular grid of cells about cell_size metres square, row 0 along the
+// southern edge of the bounding box
+pub struct Cells {
+    south: f64,
+    west: f64,
+    lat_step: f64,
+    lon_step: f64,
+    rows: usize,
+    columns: usize,
+    cell_size: f64,
+}
+
+impl Cells {
+    pub fn new(bounding_box: BoundingBox, cell_size: f64) -> Result<Self> {
+        if !cell_size.is_finite() || cell_size <= 0.0 {
+            return Err(Error::InvalidInput(
+                "The cell size must be a number of metres above 0".to_string(),
+            ));
+        }
+        let middle = (bounding_box.0 + bounding_box.2) / 2.0;
+        let lat_step = cell_size / METRES_PER_DEGREE;
+        let lon_step = lat_step / middle.to_radians().cos();
+        let rows = ((bounding_box.2 - bounding_box.0) / lat_step)
+            .ceil()
+            .max(1.0);
+        let columns = ((bounding_box.3 - bounding_box.1) / lon_step)
+            .ceil()
+            .max(1.0);
+        // also catches a NaN count from a degenerate bounding box
+        let count = rows * columns;
+        if count.is_nan() || count > MAX_CELLS {
+            return Err(Error::InvalidInput(format!(
+                "A {} m cell size would give {:.0} cells, over the {} allowed, use a larger --cell-size",
+                cell_size, count, MAX_CELLS
+            )));
+        }
+        Ok(Cells {
+            south: bounding_box.0,
+            west: bounding_box.1,
+            lat_step,
+            lon_step,
+            rows: rows as usize,
+            columns: columns as usize,
+            cell_size,
+        })
+    }
+
+    fn centre(&self, row: usize, column: usize) -> (f64, f64) {
+        (
+            self.south + (row as f64 + 0.5) * self.lat_step,
+            self.west + (column as f64 + 0.5) * self.lon_step,
+        )
+    }
+
+    // The cell's outline as a closed ring of [lon, lat] points
+    fn ring(&self, row: usize, column: usize) -> Vec<[f64; 2]> {
+        let south = self.south + row as f64 * self.lat_step;
+        let west = self.west + column as f64 * self.lon_step;
+        let (north, east) = (south + self.lat_step, west + self.lon_step);
+        vec![
+            [west, south],
+            [east, south],
+            [east, north],
+            [west, north],
+            [west, south],
+        ]
+    }
+}
+
+// The area the ways span, for caches without a manifest
+pub fn extent(highways: &[Way]) -> BoundingBox {
+    highways
+        .iter()
+        .flat_map(|highway| highway.nodes.iter())
+        .fold(
+            (f64::MAX, f64::MAX, f64::MIN, f64::MIN),
+            |(south, west, north, east), node| {
+                let (lat, lon) = (
+                    f64::from_bits(node.coordinate.0),
+                    f64::from_bits(node.coordinate.1),
+                );
+                (south.min(lat), west.min(lon), north.max(lat), east.max(lon))
+            },
+        )
+}
+
+// The categories reachable within the time limit from each cell, row by row,
+// or None for cells with no walkable node within a cell's width of their
+// centre, such as water. Cells are snapped to their nearest node and each
+// node is searched once, however many cells share it.
+pub fn coverage(
+    amenities: Vec<Node>,
+    highways: Vec<Way>,
+    cells: &Cells,
+    time_limit: f64,
+    profile: Profile,
+    dem: &[Grid],
+) -> Result<Vec<Option<BTreeSet<Category>>>> {
+    let (amenities, highways, highway_nodes, nodes_lut): MapData =
+        filter_map_data(amenities, highways, profile);
+    if highway_nodes.is_empty() {
+        return Err(Error::OriginNotSnappable(cells.centre(0, 0)));
+    }
+    let (search_tree, entries) = create_kdtree(highway_nodes.clone());
+    let snapped: Vec<Option<usize>> = (0..cells.rows)
+        .flat_map(|row| (0..cells.columns).map(move |column| (row, column)))
+        .map(|(row, column)| {
+            let centre = cells.centre(row, column);
+            let nearest: NearestNeighbour<f64, u64> =
+                search_tree.nearest_one::<SquaredEuclidean>(&[centre.0, centre.1]);
+            let node = &highway_nodes[&entries[nearest.item as usize]];
+            let distance = Location::new(centre.0, centre.1).kilometers_to(&Location::new(
+                f64::from_bits(node.coordinate.0),
+                f64::from_bits(node.coordinate.1),
+            )) * 1000.0;
+            if distance > cells.cell_size {
+                None
+            } else {
+                Some(get_graph_id(node.id, &nodes_lut))
+            }
+        })
+        .collect();
+    let amenity_categories: Vec<(usize, Category)> = amenities
+        .iter()
+        .filter_map(|amenity| Some((get_graph_id(amenity.id, &nodes_lut), amenity.category?)))
+        .collect();
+    let graph = create_graph(
+        amenities,
+        highways,
+        highway_nodes,
+        nodes_lut,
+        (search_tree, entries),
+        profile,
+        dem,
+    );
+    let adjacency = adjacency(&graph);
+    let limit = seconds_to_weight(time_limit);
+    let origins: HashSet<usize> = snapped.iter().flatten().copied().collect();
+    let reached: HashMap<usize, BTreeSet<Category>> = origins
+        .into_par_iter()
+        .map(|origin| {
+            let (distances, _) = bounded_search(&adjacency, origin, limit);
+            let categories: BTreeSet<Category> = amenity_categories
+                .iter()
+                .filter(|(graph_id, _)| distances.contains_key(graph_id))
+                .map(|(_, category)| *category)
+                .collect();
+            (origin, categories)
+        })
+        .collect();
+    Ok(snapped
+        .iter()
+        .map(|origin| origin.map(|origin| reached[&origin].clone()))
+        .collect())
+}
+
+fn essential_reached(categories: &BTreeSet<Category>) -> usize {
+    ESSENTIAL_CATEGORIES
+        .iter()
+        .filter(|category| categories.contains(category))
+        .count()
+}
+
+// A FeatureCollection with a square Polygon per cell, carrying the share of
+// essential categories in reach and every category reached. Cells without
+// data are left out.
+pub fn write_heatmap_geojson(
+    path: &Path,
+    cells: &Cells,
+    coverage: &[Option<BTreeSet<Category>>],
+    time_limit: f64,
+) -> Result<()> {
+    let features: Vec<serde_json::Value> = coverage
+        .iter()
+        .enumerate()
+        .filter_map(|(index, categories)| {
+            let categories = categories.as_ref()?;
+            let (row, column) = (index / cells.columns, index % cells.columns);
+            let reached = essential_reached(categories);
+            Some(json!({
+                "type": "Feature",
+                "geometry": {"type": "Polygon", "coordinates": [cells.ring(row, column)]},
+                "properties": {
+                    "score": reached as f64 / ESSENTIAL_CATEGORIES.len() as f64,
+                    "essential_reached": reached,
+                    "categories": categories,
+                    "limit_s": time_limit,
+                },
+            }))
+        })
+        .collect();
+    let feature_collection = json!({
+        "type": "FeatureCollection",
+        "features": features,
+    });
+    let mut heatmap_path = File::create(path)?;
+    write!(
+        &mut heatmap_path,
+        "{}",
+        serde_json::to_string_pretty(&feature_collection).unwrap()
+    )?;
+    Ok(())
+}
+
+// An ESRI ASCII grid of how many essential categories each cell reaches.
+// Cells are square in metres rather than degrees, so the header gives
+// separate dx and dy as GDAL expects.
+pub fn write_heatmap_ascii(
+    path: &Path,
+    cells: &Cells,
+    coverage: &[Option<BTreeSet<Category>>],
+) -> Result<()> {
+    let mut heatmap_path = File::create(path)?;
+    writeln!(heatmap_path, "ncols {}", cells.columns)?;
+    writeln!(heatmap_path, "nrows {}", cells.rows)?;
+    writeln!(heatmap_path, "xllcorner {}", cells.west)?;
+    writeln!(heatmap_path, "yllcorner {}", cells.south)?;
+    writeln!(heatmap_path, "dx {}", cells.lon_step)?;
+    writeln!(heatmap_path, "dy {}", cells.lat_step)?;
+    writeln!(heatmap_path, "NODATA_value {}", NO_DATA)?;
+    // the grid is written from the northern edge down
+    for row in (0..cells.rows).rev() {
+        let line: Vec<String> = coverage[row * cells.columns..(row + 1) * cells.columns]
+            .iter()
+            .map(|categories| {
+                categories
+                    .as_ref()
+                    .map(|categories| essential_reached(categories) as i32)
+                    .unwrap_or(NO_DATA)
+                    .to_string()
+            })
+            .collect();
+        writeln!(heatmap_path, "{}", line.join(" "))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // About 2.2 km north to south and 1.3 km east to west
+    const BOUNDING_BOX: BoundingBox = (55.94, -3.2, 55.96, -3.18);
+
+    #[test]
+    fn covers_the_bounding_box() {
+        let cells = Cells::new(BOUNDING_BOX, 200.0).unwrap();
+        assert_eq!((cells.rows, cells.columns), (12, 7));
+        assert_eq!(cells.ring(0, 0)[0], [-3.2, 55.94]);
+    }
+
+    #[test]
+    fn rejects_cell_sizes_that_are_not_positive_and_finite() {
+        for cell_size in [0.0, -200.0, f64::NAN, f64::INFINITY] {
+            assert!(matches!(
+                Cells::new(BOUNDING_BOX, cell_size),
+                Err(Error::InvalidInput(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_too_many_cells() {
+        // 1 m cells over a 100 km radius
+        let bounding_box = (55.05, -4.8, 56.85, -1.6);
+        assert!(matches!(
+            Cells::new(bounding_box, 1.0),
+            Err(Error::InvalidInput(_))
+        ));
+    }
+}
@@ -0,0 +1,101 @@
+use std::fs;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use bimap::BiHashMap;
+use fast_paths::FastGraph;
+
+use crate::error::Result;
+use crate::profile::Profile;
+use crate::{create_graph, create_kdtree, filter_map_data, MapData, Node, Way};
+
+// A contraction hierarchy over a whole cache for one profile, with the lookup
+// table between its node ids and OSM ids
+pub type PreparedGraph = (FastGraph, BiHashMap<usize, usize>);
+
+// Each profile and speed gets its own file, as both change the edge weights
+fn graph_path(directory: &Path, profile: Profile) -> PathBuf {
+    let mode = format!("{:?}", profile.mode).to_lowercase();
+    directory.join(format!("graph_{}_{}.bin", mode, profile.speed))
+}
+
+// Contracts the graph for every way in the cache the profile can use and
+// saves it in the cache's directory
+pub fn prepare_cache_graph(
+    directory: &Path,
+    amenities: Vec<Node>,
+    highways: Vec<Way>,
+    profile: Profile,
+) -> Result<PreparedGraph> {
+    let (amenities, highways, highway_nodes, nodes_lut): MapData =
+        filter_map_data(amenities, highways, profile);
+    let nearest_nodes = create_kdtree(highway_nodes.clone());
+    let input_graph = create_graph(
+        amenities,
+        highways,
+        highway_nodes,
+        nodes_lut.clone(),
+        nearest_nodes,
+        profile,
+        &[],
+    );
+    let fast_graph = fast_paths::prepare(&input_graph);
+    let lut: Vec<(usize, usize)> = nodes_lut
+        .iter()
+        .map(|(left, right)| (*left, *right))
+        .collect();
+    let file = File::create(graph_path(directory, profile))?;
+    let mut writer = BufWriter::new(file);
+    bincode::serialize_into(&mut writer, &(&fast_graph, &lut))?;
+    writer.flush()?;
+    Ok((fast_graph, nodes_lut))
+}
+
+// The saved hierarchy for the profile, or None if it hasn't been prepared or
+// can't be read
+pub fn load_cache_graph(directory: &Path, profile: Profile) -> Option<PreparedGraph> {
+    let file = File::open(graph_path(directory, profile)).ok()?;
+    let (fast_graph, lut): (FastGraph, Vec<(usize, usize)>) =
+        bincode::deserialize_from(BufReader::new(file)).ok()?;
+    Some((fast_graph, lut.into_iter().collect()))
+}
+
+// Hierarchies go stale once the cache they were built from is rewritten
+pub fn remove_cache_graphs(directory: &Path) {
+    if let Ok(entries) = fs::read_dir(directory) {
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with("graph_") && name.ends_with(".bin") {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::category::Category;
+
+    #[test]
+    fn prepares_a_cache_without_usable_ways() {
+        let directory = std::env::temp_dir().join("prepare_without_ways");
+        fs::create_dir_all(&directory).unwrap();
+        let pharmacy = Node {
+            name: Some("\"Boots\"".to_string()),
+            coordinate: (55.95_f64.to_bits(), (-3.19_f64).to_bits()),
+            id: 10,
+            category: Some(Category::Healthcare),
+            tags: BTreeMap::new(),
+        };
+        let profile = Profile::from_name("adult").unwrap();
+        let prepared = prepare_cache_graph(&directory, vec![pharmacy], Vec::new(), profile);
+        let loaded = load_cache_graph(&directory, profile);
+        let _ = fs::remove_dir_all(&directory);
+        assert!(prepared.is_ok());
+        assert!(loaded.is_some());
+    }
+}
@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+
+use fast_paths::InputGraph;
+use geo::{ConcaveHull, MultiPoint, Point};
+use serde_json::json;
+
+use crate::error::Result;
+
+// Lower values follow the network more tightly, higher values approach the
+// convex hull
+const CONCAVITY: f64 = 2.0;
+
+// Every reachable node plus, for edges only partly walkable within the limit,
+// the point along the edge where the limit runs out
+pub fn reachable_points(
+    graph: &InputGraph,
+    distances: &HashMap<usize, usize>,
+    node_coordinates: &HashMap<usize, (f64, f64)>,
+    limit: usize,
+) -> Vec<(f64, f64)> {
+    let mut points: Vec<(f64, f64)> = distances
+        .keys()
+        .filter_map(|node| node_coordinates.get(node).copied())
+        .collect();
+    for edge in graph.get_edges().iter() {
+        let start_distance = match distances.get(&edge.from) {
+            Some(distance) => *distance,
+            None => continue,
+        };
+        if start_distance + edge.weight <= limit {
+            continue;
+        }
+        if let (Some(start), Some(end)) = (
+            node_coordinates.get(&edge.from),
+            node_coordinates.get(&edge.to),
+        ) {
+            let fraction = (limit - start_distance) as f64 / edge.weight as f64;
+            points.push((
+                start.0 + (end.0 - start.0) * fraction,
+                start.1 + (end.1 - start.1) * fraction,
+            ));
+        }
+    }
+    points
+}
+
+// Concave hull around the points as a closed (lat, lon) ring, or None if there
+// are too few points to enclose an area
+pub fn create_isochrone(points: &[(f64, f64)]) -> Option<Vec<(f64, f64)>> {
+    if points.len() < 3 {
+        return None;
+    }
+    let multi_point: MultiPoint<f64> = points
+        .iter()
+        .map(|point| Point::new(point.1, point.0))
+        .collect();
+    let polygon = multi_point.concave_hull(CONCAVITY);
+    Some(
+        polygon
+            .exterior()
+            .coords()
+            .map(|coordinate| (coordinate.y, coordinate.x))
+            .collect(),
+    )
+}
+
+pub fn write_isochrone(
+    path: String,
+    origin: (f64, f64),
+    polygon: &[(f64, f64)],
+    time_limit: f64,
+) -> Result<()> {
+    let ring: Vec<[f64; 2]> = polygon.iter().map(|point| [point.1, point.0]).collect();
+    let feature_collection = json!({
+        "type": "FeatureCollection",
+        "features": [
+            {
+                "type": "Feature",
+                "geometry": {"type": "Polygon", "coordinates": [ring]},
+                "properties": {"limit_s": time_limit},
+            },
+            {
+                "type": "Feature",
+                "geometry": {"type": "Point", "coordinates": [origin.1, origin.0]},
+                "properties": {"origin": true},
+            },
+        ],
+    });
+    let mut isochrone_path = File::create(path)?;
+    write!(
+        &mut isochrone_path,
+        "{}",
+        serde_json::to_string_pretty(&feature_collection).unwrap()
+    )?;
+    Ok(())
+}
@@ -1,9 +1,9 @@
 use core::f64;
-use std::collections::{HashMap, HashSet};
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
 
 use bimap::{BiHashMap, BiMap};
-use clap::Parser;
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use fast_paths::InputGraph;
 use geocoding::openstreetmap::{OpenstreetmapParams, OpenstreetmapResponse};
 use geocoding::Openstreetmap;
@@ -11,22 +11,280 @@ use haversine_redux::Location;
 use kiddo::{ImmutableKdTree, NearestNeighbour, SquaredEuclidean};
 use rayon::prelude::*;
 use reqwest::blocking::Client;
+use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use serde_json::Value;
 use std::fs;
 use std::fs::File;
-use std::io::BufReader;
-use std::io::{stdin as input, Write};
-use std::usize;
+use std::io::{stdin as input, IsTerminal, Write};
+use std::process;
+use std::str::FromStr;
 
+mod batch;
+mod cache;
+mod category;
+mod elevation;
+mod error;
+mod geocode;
+mod geojson;
+mod heatmap;
+mod hierarchy;
+mod isochrone;
+mod maintenance;
+mod osm_xml;
+mod pbf;
+mod profile;
+mod search;
+mod source;
+mod tiles;
+
+use batch::run_batch;
+use cache::{
+    cache_exists, convert_json_cache, covers, find_covering_cache, list_manifests, query_hash,
+    read_binary_addresses, read_binary_cache, read_json_addresses, read_json_cache, read_manifest,
+    write_binary_cache, write_manifest, Manifest, CACHE_VERSION,
+};
+use category::{categorise, category_key, print_completeness_report, Category};
+use elevation::{elevation, load_dem, Grid};
+use error::{Error, Result};
+use geocode::{find_address, response_to_addresses, Address};
+use geojson::amenities_to_geojson;
+use heatmap::{coverage, extent, write_heatmap_ascii, write_heatmap_geojson, Cells};
+use hierarchy::{load_cache_graph, prepare_cache_graph, remove_cache_graphs, PreparedGraph};
+use isochrone::{create_isochrone, reachable_points, write_isochrone};
+use maintenance::{inspect_cache, list_caches, purge_caches, refresh_cache};
+use profile::{
+    is_cycleable_highway, is_walkable_highway, seconds_to_weight, slope_factor, weight_to_seconds,
+    Mode, Profile, ALL_MODES, DEFAULT_PROFILE,
+};
+use search::{path_to, shortest_path_tree};
+use source::{CacheSource, InMemorySource, MapDataSource, OverpassSource, TileSource};
+
+// Run without a command to be asked for everything interactively
 #[derive(Parser)]
+#[command(about = "Finds the amenities within walking distance of an address")]
 struct Cli {
-    arg1: Option<String>,
-    arg2: Option<String>,
-    arg3: Option<String>,
-    arg4: Option<String>,
-    arg5: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Output format for the results, json by default, or for heatmaps geojson by default
+    /// or ascii
+    #[arg(long, global = true, value_enum)]
+    format: Option<Format>,
+    /// File to write the results to, ./nearby_poi.json or ./nearby_poi.geojson by default
+    #[arg(long, global = true)]
+    output: Option<PathBuf>,
+    /// Sort the results by one of their distances, nearest first
+    #[arg(long, global = true, value_enum, default_value_t = Sort::NetworkDistance)]
+    sort: Sort,
+    /// Travel time limit in minutes, used instead of a maximum distance
+    #[arg(long, global = true, value_parser = parse_minutes)]
+    minutes: Option<f64>,
+    /// Travel profile: adult, elderly, child, cycling or wheelchair, optionally with a
+    /// custom speed in m/s such as cycling:5.5, or a bare walking speed
+    #[arg(long, global = true, default_value = DEFAULT_PROFILE)]
+    profile: String,
+    /// Include the walking route to each amenity in the output
+    #[arg(long, global = true)]
+    routes: bool,
+    /// Write the area reachable within the distance as a GeoJSON polygon to this file
+    #[arg(long, global = true)]
+    isochrone: Option<String>,
+    /// Directory of SRTM .hgt or GeoTIFF elevation tiles, to slow travel uphill
+    #[arg(long, global = true)]
+    dem: Option<PathBuf>,
+    /// Write caches without zstd compression
+    #[arg(long, global = true)]
+    no_compression: bool,
+    /// When searching online, keep what is fetched in a tile cache shared by all searches
+    #[arg(long, global = true)]
+    tiles: bool,
+    /// Never ask for anything on stdin, failing instead. On whenever stdin isn't a terminal
+    #[arg(long, global = true)]
+    non_interactive: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Format {
+    Json,
+    Geojson,
+    Ascii,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+#[value(rename_all = "snake_case")]
+enum Sort {
+    NetworkDistance,
+    WalkingTime,
+    StraightLineDistance,
+}
+
+// How far a search reaches, as given to --distance
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Distance {
+    Metres(f64),
+    Minutes(f64),
+}
+
+// Largest cache radius in metres, beyond which Overpass gives up anyway
+const MAX_RADIUS: u32 = 100000;
+
+fn parse_positive(text: &str) -> Option<f64> {
+    text.trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|value| value.is_finite() && *value > 0.0)
+}
+
+// Takes metres ("1500") or minutes ("15min")
+fn parse_distance(text: &str) -> std::result::Result<Distance, String> {
+    let text = text.trim();
+    let (number, distance): (&str, fn(f64) -> Distance) = match text.strip_suffix("min") {
+        Some(minutes) => (minutes, Distance::Minutes),
+        None => (text, Distance::Metres),
+    };
+    parse_positive(number).map(distance).ok_or(format!(
+        "expected metres such as 1500 or a time such as 15min, found \"{}\"",
+        text
+    ))
+}
+
+fn parse_minutes(text: &str) -> std::result::Result<f64, String> {
+    parse_positive(text).ok_or(format!(
+        "expected a number of minutes above 0, found \"{}\"",
+        text
+    ))
+}
+
+fn parse_radius(text: &str) -> std::result::Result<u32, String> {
+    match text.trim().parse::<u32>() {
+        Ok(radius) if (1..=MAX_RADIUS).contains(&radius) => Ok(radius),
+        _ => Err(format!(
+            "expected a whole number of metres from 1 to {}, found \"{}\"",
+            MAX_RADIUS, text
+        )),
+    }
+}
+
+// Where a search starts and how far it reaches
+#[derive(Args)]
+struct SearchArgs {
+    /// Address to search from
+    #[arg(long, required_unless_present = "lat", conflicts_with = "lat")]
+    address: Option<String>,
+    /// Latitude of the origin, instead of an address
+    #[arg(long, requires = "lon", allow_negative_numbers = true)]
+    lat: Option<f64>,
+    /// Longitude of the origin, instead of an address
+    #[arg(long, requires = "lat", allow_negative_numbers = true)]
+    lon: Option<f64>,
+    /// Maximum distance in metres, or a time such as 15min. Defaults to 1500
+    /// metres when --minutes isn't given either
+    #[arg(long, value_parser = parse_distance)]
+    distance: Option<Distance>,
+}
+
+impl SearchArgs {
+    // The origin in the form get_origin_coordinates takes
+    fn origin(&self) -> String {
+        match (&self.address, self.lat, self.lon) {
+            (Some(address), _, _) => address.clone(),
+            (None, Some(lat), Some(lon)) => format!("{},{}", lat, lon),
+            _ => String::new(),
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Search around one origin online, or in a local .osm.pbf or .osm file
+    Query {
+        #[command(flatten)]
+        search: SearchArgs,
+        /// Search this .osm.pbf or .osm file instead of querying Overpass
+        #[arg(long)]
+        file: Option<PathBuf>,
+    },
+    /// Search around every origin in a CSV file over one cache, writing a row
+    /// per origin with the count and nearest distance of each category to
+    /// --output, ./batch.csv by default
+    Batch {
+        /// CSV file with a header row and an address column, or lat and lon
+        /// columns, for each origin
+        #[arg(long)]
+        input: PathBuf,
+        /// City name or lat,lon coordinates of the cache, built first if it doesn't exist
+        #[arg(long)]
+        city: String,
+        /// Radius in metres of the cache
+        #[arg(long, value_parser = parse_radius)]
+        radius: u32,
+        /// Maximum distance in metres, or a time such as 15min. Defaults to 1500
+        /// metres when --minutes isn't given either
+        #[arg(long, value_parser = parse_distance)]
+        distance: Option<Distance>,
+    },
+    /// Map which categories are in reach across a whole cache, writing a grid
+    /// of cells to --output, ./heatmap.geojson or with --format ascii
+    /// ./heatmap.asc by default
+    Heatmap {
+        /// City name or lat,lon coordinates of the cache, built first if it doesn't exist
+        #[arg(long)]
+        city: String,
+        /// Radius in metres of the cache
+        #[arg(long, value_parser = parse_radius)]
+        radius: u32,
+        /// Width of each cell in metres
+        #[arg(long, default_value_t = 200.0)]
+        cell_size: f64,
+        /// Maximum distance in metres, or a time such as 15min. Defaults to 15
+        /// minutes when --minutes isn't given either
+        #[arg(long, value_parser = parse_distance)]
+        distance: Option<Distance>,
+    },
+    /// Build, search and manage the caches in ./Cache
+    Cache {
+        #[command(subcommand)]
+        action: CacheAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum CacheAction {
+    /// Download everything within a radius of a city into a cache
+    Build {
+        /// City name or lat,lon coordinates at the centre of the cache
+        #[arg(long)]
+        city: String,
+        /// Radius in metres to download around the centre
+        #[arg(long, value_parser = parse_radius)]
+        radius: u32,
+    },
+    /// Search a cache, building it first if it doesn't exist. Without --city
+    /// and --radius, whichever cache covers the origin is searched.
+    Query {
+        #[command(flatten)]
+        search: SearchArgs,
+        /// City name or lat,lon coordinates the cache was built for
+        #[arg(long, requires = "radius")]
+        city: Option<String>,
+        /// Radius in metres the cache was built with
+        #[arg(long, requires = "city", value_parser = parse_radius)]
+        radius: Option<u32>,
+    },
+    /// List every cache with its size, coverage and age
+    List,
+    /// Print what one cache holds, given its folder name such as Edinburgh_5000
+    Inspect { name: String },
+    /// Download a cache again, replacing the old one only once the new one is complete
+    Refresh { name: String },
+    /// Convert the JSON cache in this directory to the binary format
+    Convert { directory: PathBuf },
+    /// Delete caches and tiles older than a number of days
+    Purge {
+        #[arg(long)]
+        older_than: f64,
+    },
 }
 
 #[derive(Eq, Hash, PartialEq, Debug, Clone, Serialize, Deserialize)]
@@ -35,72 +293,308 @@ struct Node {
     name: Option<String>,
     coordinate: (u64, u64),
     id: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    category: Option<Category>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    tags: BTreeMap<String, String>,
 }
 
+// Node tags that affect whether someone can reach or pass through the node
+const NODE_TAGS: [&str; 3] = ["wheelchair", "kerb", "kerb:height"];
+
+// Furthest in metres an origin can be from a usable path, beyond which it is
+// more likely in a field or the sea than somewhere the profile can start from
+const MAX_SNAP_DISTANCE: f64 = 500.0;
+
 #[derive(Eq, Hash, PartialEq, Debug, Clone, Serialize, Deserialize)]
 struct Way {
     id: usize,
     nodes: Vec<Node>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    tags: BTreeMap<String, String>,
 }
 
-fn get_overpass_json_response(
-    coordinates: (f64, f64),
-    deltay: f64,
-    deltax: f64,
-    url: String,
-) -> Value {
-    let bounding_box = (
+// An amenity within reach of the origin, with how far it is along the
+// network, how long it takes to walk there and how far it is as the crow flies
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+struct ReachableAmenity {
+    #[serde(flatten)]
+    node: Node,
+    network_distance: f64,
+    walking_time: f64,
+    straight_line_distance: f64,
+    // (lat, lon) of every node along the walking route from the origin
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    route: Option<Vec<(f64, f64)>>,
+}
+
+type MapData = (
+    Vec<Node>,
+    Vec<Way>,
+    HashMap<usize, Node>,
+    BiHashMap<usize, usize>,
+);
+
+// The reachable amenities, and the isochrone
+// polygon when one was asked for
+type QueryResult = (Vec<ReachableAmenity>, Option<Vec<(f64, f64)>>);
+
+// (south, west, north, east), the same ordering Overpass uses
+type BoundingBox = (f64, f64, f64, f64);
+
+fn get_bounding_box(coordinates: (f64, f64), deltay: f64, deltax: f64) -> BoundingBox {
+    (
         (coordinates.0 - deltay),
         (coordinates.1 - deltax),
         (coordinates.0 + deltay),
         (coordinates.1 + deltax),
-    );
-    let bounding_box_string = format!(
-        "({},{},{},{})",
-        bounding_box.0, bounding_box.1, bounding_box.2, bounding_box.3
-    );
-    let query = format!(
+    )
+}
+
+// The bounding box that contains every point within `distance` metres of the
+// coordinates
+fn get_search_bounding_box(coordinates: (f64, f64), distance: f64) -> BoundingBox {
+    let deltay: f64 = (distance / 111000.0).abs();
+    let deltax: f64 = (deltay / coordinates.0.to_radians().cos()).abs();
+    get_bounding_box(coordinates, deltay, deltax)
+}
+
+fn in_bounding_box(coordinates: (f64, f64), bounding_box: BoundingBox) -> bool {
+    coordinates.0 >= bounding_box.0
+        && coordinates.1 >= bounding_box.1
+        && coordinates.0 <= bounding_box.2
+        && coordinates.1 <= bounding_box.3
+}
+
+// Mirrors the filters of the Overpass query below, so that offline sources
+// select exactly the elements a live query would have returned.
+fn is_selected_element(element_type: &str, tags: &HashMap<String, String>) -> bool {
+    if element_type == "relation" {
+        return false;
+    }
+    if let Some(tag_type) = tags.get("type") {
+        if tag_type == "relation" || tag_type == "multipolygon" {
+            return false;
+        }
+    }
+    if categorise(tags).is_some() {
+        return true;
+    }
+    if element_type == "node" {
+        return tags.contains_key("kerb");
+    }
+    element_type == "way" && (is_walkable_highway(tags) || is_cycleable_highway(tags))
+}
+
+// Builds a node element in the shape Overpass returns for `out geom`.
+fn node_element(id: u64, coordinates: (f64, f64), tags: &HashMap<String, String>) -> Value {
+    json!({
+        "type": "node",
+        "id": id,
+        "lat": coordinates.0,
+        "lon": coordinates.1,
+        "tags": tags,
+    })
+}
+
+// Builds a way element in the shape Overpass returns for `out geom`, with the
+// geometry taken from the already resolved coordinates of its nodes.
+fn way_element(id: u64, nodes: &[(u64, (f64, f64))], tags: &HashMap<String, String>) -> Value {
+    let mut bounds = (f64::MAX, f64::MAX, f64::MIN, f64::MIN);
+    for (_, coordinates) in nodes.iter() {
+        bounds.0 = bounds.0.min(coordinates.0);
+        bounds.1 = bounds.1.min(coordinates.1);
+        bounds.2 = bounds.2.max(coordinates.0);
+        bounds.3 = bounds.3.max(coordinates.1);
+    }
+    let node_ids: Vec<u64> = nodes.iter().map(|node| node.0).collect();
+    let geometry: Vec<Value> = nodes
+        .iter()
+        .map(|node| json!({"lat": node.1 .0, "lon": node.1 .1}))
+        .collect();
+    json!({
+        "type": "way",
+        "id": id,
+        "bounds": {
+            "minlat": bounds.0,
+            "minlon": bounds.1,
+            "maxlat": bounds.2,
+            "maxlon": bounds.3,
+        },
+        "nodes": node_ids,
+        "geometry": geometry,
+        "tags": tags,
+    })
+}
+
+// The Overpass query for everything inside the bounding box, given as the
+// "(south,west,north,east)" text Overpass expects
+fn get_overpass_query(
+    bounding_box_string: &str,
+    include_addresses: bool,
+    modes: &[Mode],
+) -> String {
+    // addresses are only needed to build the offline geocoder of a cache
+    let address_filter = if include_addresses {
+        format!(
+            "nwr[\"addr:housenumber\"][type!=relation][type!=multipolygon]{};",
+            bounding_box_string
+        )
+    } else {
+        "".to_string()
+    };
+    let highway_filter: String = modes
+        .iter()
+        .flat_map(|mode| mode.overpass_filters())
+        .map(|filter| format!("{}{};", filter, bounding_box_string))
+        .collect::<Vec<String>>()
+        .join("\n    ");
+    format!(
         r##"
 [out:json]
 [timeout:60];
 (
     nwr["amenity"][type!=relation][type!=multipolygon]{bbox};
     nwr["shop"][type!=relation][type!=multipolygon]{bbox};
-    way[highway][highway!=service][highway=footway][access!=private][type!=relation][type!=multipolygon]{bbox};
-    way[highway][highway!=service][sidewalk][access!=private][type!=relation][type!=multipolygon]{bbox};
+    nwr["leisure"][type!=relation][type!=multipolygon]{bbox};
+    nwr["tourism"~"^(museum|gallery)$"][type!=relation][type!=multipolygon]{bbox};
+    nwr["public_transport"][type!=relation][type!=multipolygon]{bbox};
+    nwr["railway"="station"][type!=relation][type!=multipolygon]{bbox};
+    node["highway"="bus_stop"]{bbox};
+    {highways}
+    {addresses}
 );
 out geom;
 "##,
-        bbox = bounding_box_string
+        bbox = bounding_box_string,
+        highways = highway_filter,
+        addresses = address_filter
+    )
+}
+
+fn get_overpass_json_response(
+    bounding_box: BoundingBox,
+    url: String,
+    include_addresses: bool,
+    modes: &[Mode],
+) -> Result<Value> {
+    let bounding_box_string = format!(
+        "({},{},{},{})",
+        bounding_box.0, bounding_box.1, bounding_box.2, bounding_box.3
     );
+    let query = get_overpass_query(&bounding_box_string, include_addresses, modes);
     println!("{}", query);
-    let res = Client::new().post(url).body(query).send();
-    let response: Value = res.unwrap().json().unwrap();
-    return response;
+    let res = Client::new()
+        .post(url)
+        .body(query)
+        .send()
+        .map_err(|error| {
+            if error.is_timeout() {
+                Error::OverpassTimeout
+            } else {
+                Error::OverpassUnavailable(error.to_string())
+            }
+        })?;
+    match res.status() {
+        StatusCode::TOO_MANY_REQUESTS => return Err(Error::OverpassRateLimited),
+        StatusCode::GATEWAY_TIMEOUT => return Err(Error::OverpassTimeout),
+        status if !status.is_success() => {
+            return Err(Error::OverpassUnavailable(status.to_string()))
+        }
+        _ => {}
+    }
+    let response: Value = res
+        .json()
+        .map_err(|error| Error::MalformedResponse(error.to_string()))?;
+    // Overpass reports queries that ran out of time or memory in a remark
+    // alongside whatever it managed to return
+    if let Some(remark) = response["remark"].as_str() {
+        if remark.contains("timed out") {
+            return Err(Error::OverpassTimeout);
+        }
+        if remark.contains("error") {
+            return Err(Error::OverpassUnavailable(remark.to_string()));
+        }
+    }
+    if !response["elements"].is_array() {
+        return Err(Error::MalformedResponse(
+            "the response has no elements".to_string(),
+        ));
+    }
+    Ok(response)
 }
 
-fn get_address_coordinates(address: String) -> (f64, f64) {
+fn get_address_coordinates(address: String) -> Result<(f64, f64)> {
     let osm = Openstreetmap::new();
     let params = OpenstreetmapParams::new(&address)
         .with_addressdetails(true)
         .build();
-    let res: OpenstreetmapResponse<f64> = osm.forward_full(&params).unwrap();
-    let coordinates: (f64, f64) = res.features[0].geometry.coordinates;
+    let res: OpenstreetmapResponse<f64> = osm
+        .forward_full(&params)
+        .map_err(|error| Error::GeocoderUnavailable(error.to_string()))?;
+    let coordinates: (f64, f64) = match res.features.first() {
+        Some(feature) => feature.geometry.coordinates,
+        None => return Err(Error::GeocodeNotFound(address)),
+    };
     let new_coordinates: (f64, f64) = (coordinates.1, coordinates.0);
-    return new_coordinates;
+    Ok(new_coordinates)
+}
+
+// Parses a single decimal degree value, optionally followed by a hemisphere
+// letter, e.g. "55.95", "-3.18" or "3.18W".
+fn parse_degrees(value: &str, positive: char, negative: char) -> Option<f64> {
+    let value = value.trim();
+    let last = value.chars().last()?.to_ascii_uppercase();
+    if last == positive || last == negative {
+        let number = value[..value.len() - 1].trim().parse::<f64>().ok()?;
+        if number < 0.0 {
+            return None;
+        }
+        return Some(if last == negative { -number } else { number });
+    }
+    value.parse::<f64>().ok()
 }
 
-fn get_active_url() -> String {
+// Parses a "lat,lon" or "lat lon" pair of decimal degrees
+fn parse_coordinates(origin: &str) -> Option<(f64, f64)> {
+    let parts: Vec<&str> = if origin.contains(',') {
+        origin.split(',').collect()
+    } else {
+        origin.split_whitespace().collect()
+    };
+    if parts.len() != 2 {
+        return None;
+    }
+    let lat = parse_degrees(parts[0], 'N', 'S')?;
+    let lon = parse_degrees(parts[1], 'E', 'W')?;
+    if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+        return None;
+    }
+    Some((lat, lon))
+}
+
+// Resolves an origin given either as coordinates, which skips geocoding
+// entirely, or as an address. Addresses are looked up in the local address
+// index first, when there is one, and only then sent to Nominatim.
+fn get_origin_coordinates(origin: String, addresses: Option<&[Address]>) -> Result<(f64, f64)> {
+    if let Some(coordinates) = parse_coordinates(&origin) {
+        return Ok(coordinates);
+    }
+    if let Some(coordinates) = addresses.and_then(|addresses| find_address(addresses, &origin)) {
+        println!("Address found in local cache");
+        return Ok(coordinates);
+    }
+    get_address_coordinates(origin)
+}
+
+// The first Overpass server that answers a small test query
+fn get_active_url() -> Result<String> {
     let urls: Vec<String> = vec![
         "https://maps.mail.ru/osm/tools/overpass/api/interpreter".to_string(),
         "https://overpass-api.de/api/interpreter".to_string(),
         "https://overpass.kumi.systems/api/interpreter".to_string(),
     ];
-    let index = 0;
-    let mut url = &urls[index];
-    let mut valid_url = false;
-    let query = format!(
-        r##"
+    let query = r##"
     [out:json]
     [timeout:25];
     (
@@ -108,111 +602,135 @@ fn get_active_url() -> String {
     );
     out geom;
             "##
-    );
-    while valid_url != true && index < urls.len() {
-        url = &urls[index];
-        let res = Client::new().post(url).body(query.clone()).send();
-        if res.unwrap().status().is_success() {
-            valid_url = true;
+    .to_string();
+    let mut last_error = Error::OverpassUnavailable("no servers to try".to_string());
+    for url in urls.iter() {
+        match Client::new().post(url).body(query.clone()).send() {
+            Ok(res) if res.status().is_success() => return Ok(url.clone()),
+            Ok(res) if res.status() == StatusCode::TOO_MANY_REQUESTS => {
+                last_error = Error::OverpassRateLimited
+            }
+            Ok(res) => last_error = Error::OverpassUnavailable(res.status().to_string()),
+            Err(error) => last_error = Error::OverpassUnavailable(error.to_string()),
         }
     }
-    return url.clone();
+    Err(last_error)
 }
 
-fn response_to_structures(
-    response: Value,
-) -> (
-    Vec<Node>,
-    Vec<Way>,
-    HashMap<usize, Node>,
-    BiHashMap<usize, usize>,
-) {
+// The accessibility tags worth keeping on a node, everything else is dropped
+fn node_tags(tags: &HashMap<String, String>) -> BTreeMap<String, String> {
+    NODE_TAGS
+        .iter()
+        .filter_map(|key| tags.get(*key).map(|value| (key.to_string(), value.clone())))
+        .collect()
+}
+
+// Numbers in the response come back as JSON values, which become text like
+// "55.95" or, when missing, "null"
+fn parse_number<T: FromStr>(value: &Value) -> Result<T> {
+    value
+        .to_string()
+        .parse::<T>()
+        .map_err(|_| malformed_number(&value.to_string()))
+}
+
+fn malformed_number(text: &str) -> Error {
+    Error::MalformedResponse(format!("expected a number, found {}", text))
+}
+
+fn response_to_structures(response: Value) -> Result<MapData> {
     let mut failed = false;
     let mut index = 0;
     let mut amenities: Vec<Node> = Vec::new();
     let mut highway_nodes: HashMap<usize, Node> = HashMap::new();
     let mut highways: Vec<Way> = Vec::new();
-    while failed == false {
+    let mut barrier_tags: HashMap<usize, BTreeMap<String, String>> = HashMap::new();
+    while !failed {
         if response["elements"][index] != json!(null) {
-            if response["elements"][index]["tags"]["amenity"] != json!(null) {
+            let tags: HashMap<String, String> =
+                serde_json::from_value(response["elements"][index]["tags"].clone())
+                    .unwrap_or_default();
+            let category = categorise(&tags);
+            if category.is_some() {
                 let temp_name;
                 let temp_lat;
                 let temp_lon;
                 let temp_id;
-                if response["elements"][index]["type"].to_string() == r##""node""## {
-                    temp_lat = response["elements"][index]["lat"]
-                        .to_string()
-                        .parse::<f64>()
-                        .unwrap()
-                        .to_bits();
-                    temp_lon = response["elements"][index]["lon"]
-                        .to_string()
-                        .parse::<f64>()
-                        .unwrap()
-                        .to_bits();
+                if response["elements"][index]["type"] == "node" {
+                    temp_lat = parse_number::<f64>(&response["elements"][index]["lat"])?.to_bits();
+                    temp_lon = parse_number::<f64>(&response["elements"][index]["lon"])?.to_bits();
                 } else {
-                    temp_lat = ((response["elements"][index]["bounds"]["minlat"]
-                        .to_string()
-                        .parse::<f64>()
-                        .unwrap()
-                        + response["elements"][index]["bounds"]["maxlat"]
-                            .to_string()
-                            .parse::<f64>()
-                            .unwrap())
-                        / 2.0)
-                        .to_bits();
-                    temp_lon = ((response["elements"][index]["bounds"]["minlon"]
-                        .to_string()
-                        .parse::<f64>()
-                        .unwrap()
-                        + response["elements"][index]["bounds"]["maxlon"]
-                            .to_string()
-                            .parse::<f64>()
-                            .unwrap())
-                        / 2.0)
-                        .to_bits();
+                    temp_lat =
+                        ((parse_number::<f64>(&response["elements"][index]["bounds"]["minlat"])?
+                            + parse_number::<f64>(
+                                &response["elements"][index]["bounds"]["maxlat"],
+                            )?)
+                            / 2.0)
+                            .to_bits();
+                    temp_lon =
+                        ((parse_number::<f64>(&response["elements"][index]["bounds"]["minlon"])?
+                            + parse_number::<f64>(
+                                &response["elements"][index]["bounds"]["maxlon"],
+                            )?)
+                            / 2.0)
+                            .to_bits();
                 }
+                // unnamed amenities go by the tag that categorised them
                 if response["elements"][index]["tags"]["name"] != json!(null) {
                     temp_name = Some(response["elements"][index]["tags"]["name"].to_string());
-                } else if response["elements"][index]["tags"]["shop"] != json!(null) {
-                    temp_name = Some(response["elements"][index]["tags"]["shop"].to_string());
+                } else if let Some(key) = category_key(&tags) {
+                    temp_name = Some(response["elements"][index]["tags"][key].to_string());
                 } else {
                     temp_name = None;
                 }
-                if temp_name != None {
+                if temp_name.is_some() {
                     temp_id = response["elements"][index]["id"].to_string();
                     let new_node = Node {
                         name: temp_name,
                         coordinate: (temp_lat, temp_lon),
-                        id: temp_id.to_string().parse::<usize>().unwrap(),
+                        id: temp_id
+                            .parse::<usize>()
+                            .map_err(|_| malformed_number(&temp_id))?,
+                        category,
+                        tags: node_tags(&tags),
                     };
                     amenities.push(new_node);
                 }
+            } else if response["elements"][index]["type"] == "node" {
+                let tags = node_tags(&tags);
+                if !tags.is_empty() {
+                    let temp_id = response["elements"][index]["id"].to_string();
+                    barrier_tags.insert(
+                        temp_id
+                            .parse::<usize>()
+                            .map_err(|_| malformed_number(&temp_id))?,
+                        tags,
+                    );
+                }
             } else if response["elements"][index]["tags"]["highway"] != json!(null) {
                 let mut way_index = 0;
                 let mut failed_way = false;
                 let mut nodes_vec: Vec<Node> = Vec::new();
-                while failed_way == false {
+                while !failed_way {
                     if response["elements"][index]["nodes"][way_index] != json!(null) {
-                        let temp_lat;
-                        let temp_lon;
-                        let temp_id;
-                        temp_lat = response["elements"][index]["geometry"][way_index]["lat"]
-                            .to_string()
-                            .parse::<f64>()
-                            .unwrap()
-                            .to_bits();
-                        temp_lon = response["elements"][index]["geometry"][way_index]["lon"]
-                            .to_string()
-                            .parse::<f64>()
-                            .unwrap()
-                            .to_bits();
+                        let temp_lat = parse_number::<f64>(
+                            &response["elements"][index]["geometry"][way_index]["lat"],
+                        )?
+                        .to_bits();
+                        let temp_lon = parse_number::<f64>(
+                            &response["elements"][index]["geometry"][way_index]["lon"],
+                        )?
+                        .to_bits();
 
-                        temp_id = response["elements"][index]["nodes"][way_index].to_string();
+                        let temp_id = response["elements"][index]["nodes"][way_index].to_string();
                         let new_node = Node {
                             name: None,
                             coordinate: (temp_lat, temp_lon),
-                            id: temp_id.to_string().parse::<usize>().unwrap(),
+                            id: temp_id
+                                .parse::<usize>()
+                                .map_err(|_| malformed_number(&temp_id))?,
+                            category: None,
+                            tags: BTreeMap::new(),
                         };
                         nodes_vec.push(new_node.clone());
                         highway_nodes.insert(new_node.id, new_node);
@@ -223,8 +741,11 @@ fn response_to_structures(
                 }
                 let temp_id = response["elements"][index]["id"].to_string();
                 let new_way = Way {
-                    id: temp_id.to_string().parse::<usize>().unwrap(),
+                    id: temp_id
+                        .parse::<usize>()
+                        .map_err(|_| malformed_number(&temp_id))?,
                     nodes: nodes_vec,
+                    tags: tags.into_iter().collect(),
                 };
                 highways.push(new_way);
             }
@@ -233,6 +754,25 @@ fn response_to_structures(
             failed = true;
         }
     }
+    // Way geometry doesn't carry node tags, so kerbs come back as separate
+    // nodes and are matched onto the ways here
+    for highway in highways.iter_mut() {
+        for node in highway.nodes.iter_mut() {
+            if let Some(tags) = barrier_tags.get(&node.id) {
+                node.tags = tags.clone();
+                highway_nodes.insert(node.id, node.clone());
+            }
+        }
+    }
+    let nodes_lookup_table = create_lookup_table(&highway_nodes, &amenities);
+    Ok((amenities, highways, highway_nodes, nodes_lookup_table))
+}
+
+// Assigns dense graph ids, highway nodes first and amenities after them
+fn create_lookup_table(
+    highway_nodes: &HashMap<usize, Node>,
+    amenities: &[Node],
+) -> BiHashMap<usize, usize> {
     let mut adder_index: usize = 0;
     let mut nodes_lookup_table: BiHashMap<usize, usize> = BiMap::new();
     for value in highway_nodes.iter() {
@@ -243,15 +783,91 @@ fn response_to_structures(
         nodes_lookup_table.insert(adder_index, node.id);
         adder_index += 1;
     }
-    return (amenities, highways, highway_nodes, nodes_lookup_table);
+    nodes_lookup_table
+}
+
+fn node_in_bounding_box(node: &Node, bounding_box: BoundingBox) -> bool {
+    in_bounding_box(
+        (
+            f64::from_bits(node.coordinate.0),
+            f64::from_bits(node.coordinate.1),
+        ),
+        bounding_box,
+    )
+}
+
+// Ways with at least one node inside the bounding box
+fn way_in_bounding_box(highway: &Way, bounding_box: BoundingBox) -> bool {
+    highway
+        .nodes
+        .iter()
+        .any(|node| node_in_bounding_box(node, bounding_box))
+}
+
+// Keeps the amenities inside the bounding box and every way with at least one
+// node inside it, then rebuilds the highway nodes and lookup table to match.
+fn cull_map_data(amenities: Vec<Node>, highways: Vec<Way>, bounding_box: BoundingBox) -> MapData {
+    let new_amenities: Vec<Node> = amenities
+        .into_iter()
+        .filter(|amenity| node_in_bounding_box(amenity, bounding_box))
+        .collect();
+    let new_highways: Vec<Way> = highways
+        .into_par_iter()
+        .filter(|highway| way_in_bounding_box(highway, bounding_box))
+        .collect();
+    structures_from_ways(new_amenities, new_highways)
+}
+
+// Drops the ways the profile can't use, along with the nodes only they used,
+// and the amenities it can't get into
+fn filter_map_data(amenities: Vec<Node>, highways: Vec<Way>, profile: Profile) -> MapData {
+    let amenities: Vec<Node> = amenities
+        .into_iter()
+        .filter(|amenity| profile.can_enter(&amenity.tags))
+        .collect();
+    let new_highways: Vec<Way> = highways
+        .into_iter()
+        .filter(|highway| profile.way_speed(&highway.tags).is_some())
+        .collect();
+    structures_from_ways(amenities, new_highways)
+}
+
+// Rebuilds the highway nodes and lookup table to match a new set of ways
+fn structures_from_ways(amenities: Vec<Node>, highways: Vec<Way>) -> MapData {
+    let mut highway_nodes: HashMap<usize, Node> = HashMap::new();
+    for highway in highways.iter() {
+        for node in highway.nodes.iter() {
+            highway_nodes.insert(node.id, node.clone());
+        }
+    }
+    let nodes_lookup_table = create_lookup_table(&highway_nodes, &amenities);
+    (amenities, highways, highway_nodes, nodes_lookup_table)
+}
+
+// (lat, lon) of every node in the graph, keyed by graph id
+fn create_coordinate_table(
+    highway_nodes: &HashMap<usize, Node>,
+    amenities: &[Node],
+    node_lut: &BiHashMap<usize, usize>,
+) -> HashMap<usize, (f64, f64)> {
+    let mut node_coordinates: HashMap<usize, (f64, f64)> = HashMap::new();
+    for node in highway_nodes.values().chain(amenities.iter()) {
+        if let Some(graph_id) = node_lut.get_by_right(&node.id) {
+            node_coordinates.insert(
+                *graph_id,
+                (
+                    f64::from_bits(node.coordinate.0),
+                    f64::from_bits(node.coordinate.1),
+                ),
+            );
+        }
+    }
+    node_coordinates
 }
 
 fn get_graph_id(node_id: usize, node_lut: &BiHashMap<usize, usize>) -> usize {
-    let res = node_lut
-        .get_by_right(&node_id)
-        .unwrap_or(&usize::MAX)
-        .clone();
-    return res;
+    let res = *node_lut.get_by_right(&node_id).unwrap_or(&usize::MAX);
+    res
 }
 
 fn create_graph(
@@ -259,9 +875,20 @@ fn create_graph(
     highways: Vec<Way>,
     highway_nodes: HashMap<usize, Node>,
     node_lut: BiHashMap<usize, usize>,
-    neighbour_nodes: ImmutableKdTree<f64, 2>,
-    entries: Vec<usize>,
+    (neighbour_nodes, entries): (ImmutableKdTree<f64, 2>, Vec<usize>),
+    profile: Profile,
+    dem: &[Grid],
 ) -> InputGraph {
+    let elevations: HashMap<usize, f64> = highway_nodes
+        .par_iter()
+        .filter_map(|(id, node)| {
+            let coordinates = (
+                f64::from_bits(node.coordinate.0),
+                f64::from_bits(node.coordinate.1),
+            );
+            elevation(dem, coordinates).map(|height| (*id, height))
+        })
+        .collect();
     let mut total_nodes: HashMap<usize, Node> = highway_nodes.clone();
     for node in amenities.iter() {
         total_nodes.insert(node.id, node.clone());
@@ -270,10 +897,13 @@ fn create_graph(
     let road_edges: Vec<Vec<(usize, usize, usize)>> = highways
         .par_iter()
         .map(|highway| {
+            let speed = profile.way_speed(&highway.tags).unwrap_or(profile.speed);
             let mut last_node: Node = Node {
                 coordinate: (0, 0),
                 id: 0,
                 name: Some("Uninitialised".to_string()),
+                category: None,
+                tags: BTreeMap::new(),
             };
             let mut edges: Vec<(usize, usize, usize)> = Vec::new();
             for node in highway.nodes.iter() {
@@ -288,19 +918,46 @@ fn create_graph(
                         f64::from_bits(last_node.coordinate.0),
                         f64::from_bits(last_node.coordinate.1),
                     );
-                    let something = (node1, node2, (start.kilometers_to(&end) * 1000.0) as usize);
-                    edges.push(something);
+                    let distance = start.kilometers_to(&end) * 1000.0;
+                    // Rise over run walking from the last node to this one,
+                    // flat where there's no elevation data
+                    let slope = match (elevations.get(&node.id), elevations.get(&last_node.id)) {
+                        (Some(height1), Some(height2)) if distance > 0.0 => {
+                            (height1 - height2) / distance
+                        }
+                        _ => 0.0,
+                    };
+                    // Half of each end's penalty, so passing through a node
+                    // costs its full penalty once
+                    if let (Some(penalty1), Some(penalty2)) = (
+                        profile.node_penalty(&node.tags),
+                        profile.node_penalty(&last_node.tags),
+                    ) {
+                        let penalty = (penalty1 + penalty2) / 2.0;
+                        edges.push((
+                            node2,
+                            node1,
+                            seconds_to_weight(distance / (speed * slope_factor(slope)) + penalty),
+                        ));
+                        edges.push((
+                            node1,
+                            node2,
+                            seconds_to_weight(distance / (speed * slope_factor(-slope)) + penalty),
+                        ));
+                    }
                 }
                 last_node = node.clone();
             }
-            return edges;
+            edges
         })
         .collect();
     // let mut edges: Vec<(usize, usize, usize)> = Vec::new();
     // for edge_vec in road_edges.iter() {
     //     edges.concat()
     // }
-    let mut neighbour_edges: Vec<(usize, usize, usize)> = amenities
+    // with no ways there is nothing to join the amenities to
+    let amenities: &[Node] = if entries.is_empty() { &[] } else { &amenities };
+    let neighbour_edges: Vec<(usize, usize, usize)> = amenities
         .par_iter()
         .map(|node: &Node| {
             let nearest: NearestNeighbour<f64, u64> = neighbour_nodes
@@ -318,22 +975,25 @@ fn create_graph(
                 f64::from_bits(neighbour_node.coordinate.0),
                 f64::from_bits(neighbour_node.coordinate.1),
             );
-            return (
+            (
                 get_graph_id(node.id, &node_lut),
-                get_graph_id(entries[index as usize], &node_lut) as usize,
-                (start.kilometers_to(&end) * 1000.0) as usize,
-            );
+                get_graph_id(entries[index as usize], &node_lut),
+                seconds_to_weight(profile.travel_time(start.kilometers_to(&end) * 1000.0)),
+            )
         })
         .collect();
-    let mut edges = road_edges.concat();
-    edges.append(&mut neighbour_edges);
-    for edge in edges.iter() {
+    // Road edges are directed, as climbing a way takes longer than coming
+    // back down it
+    for edge in road_edges.concat().iter() {
+        input_graph.add_edge(edge.0, edge.1, edge.2);
+    }
+    for edge in neighbour_edges.iter() {
         input_graph.add_edge_bidir(edge.0, edge.1, edge.2);
     }
 
     input_graph.freeze();
     let _edge_count = input_graph.get_num_edges();
-    return input_graph;
+    input_graph
 }
 
 fn create_kdtree(highway_nodes: HashMap<usize, Node>) -> (ImmutableKdTree<f64, 2>, Vec<usize>) {
@@ -348,60 +1008,81 @@ fn create_kdtree(highway_nodes: HashMap<usize, Node>) -> (ImmutableKdTree<f64, 2
         entries_id.push(value.1.id);
     }
     let tree: ImmutableKdTree<f64, 2> = ImmutableKdTree::new_from_slice(&entries);
-    return (tree, entries_id);
+    (tree, entries_id)
 }
 
+// The node nearest the origin and the seconds it takes to get there, which
+// the graph search itself doesn't count
+fn snap_origin(
+    coordinates: (f64, f64),
+    (search_tree, entries): (&ImmutableKdTree<f64, 2>, &[usize]),
+    highway_nodes: &HashMap<usize, Node>,
+    profile: Profile,
+) -> Result<(usize, f64)> {
+    let nearest: NearestNeighbour<f64, u64> =
+        search_tree.nearest_one::<SquaredEuclidean>(&[coordinates.0, coordinates.1]);
+    let node = &highway_nodes[&entries[nearest.item as usize]];
+    let distance = Location::new(coordinates.0, coordinates.1).kilometers_to(&Location::new(
+        f64::from_bits(node.coordinate.0),
+        f64::from_bits(node.coordinate.1),
+    )) * 1000.0;
+    if distance > MAX_SNAP_DISTANCE {
+        return Err(Error::OriginNotSnappable(coordinates));
+    }
+    Ok((node.id, profile.travel_time(distance)))
+}
+
+// shortest_path gives the weight of and graph nodes along the shortest path
+// from the origin's node to a graph node, or None if it can't be reached in
+// time. origin_seconds is the time from the origin to that node.
 fn cull_amenities(
     amenities: Vec<Node>,
-    path_graph: InputGraph,
-    nearest_node: u64,
+    mut shortest_path: impl FnMut(usize) -> Option<(usize, Vec<usize>)>,
     node_lut: BiHashMap<usize, usize>,
-    distance: u64,
-) -> Vec<Node> {
+    (origin, origin_seconds): ((f64, f64), f64),
+    node_coordinates: &HashMap<usize, (f64, f64)>,
+) -> Vec<ReachableAmenity> {
     let mut amenity_hashset: HashSet<Node> = HashSet::new();
     for amenity in amenities.iter() {
         amenity_hashset.insert(amenity.clone());
     }
-    let fast_graph = fast_paths::prepare(&path_graph);
-
-    // calculate the shortest path between nodes with ID 8 and 6
 
-    let new_amenity_list: Vec<Node> = amenity_hashset
+    let origin_location = Location::new(origin.0, origin.1);
+    let new_amenity_list: Vec<ReachableAmenity> = amenity_hashset
         .iter()
         .filter_map(|node: &Node| {
-            let shortest_path = fast_paths::calc_path(
-                &fast_graph,
-                nearest_node as usize,
-                get_graph_id(node.id, &node_lut),
+            let (weight, path) = shortest_path(get_graph_id(node.id, &node_lut))?;
+            let walking_time = origin_seconds + weight_to_seconds(weight);
+            let route: Vec<(f64, f64)> = std::iter::once(origin)
+                .chain(
+                    path.iter()
+                        .filter_map(|graph_id| node_coordinates.get(graph_id).copied()),
+                )
+                .collect();
+            let location = Location::new(
+                f64::from_bits(node.coordinate.0),
+                f64::from_bits(node.coordinate.1),
             );
-            let mut safe = 0;
-
-            match shortest_path {
-                Some(p) => {
-                    // the weight of the shortest path
-                    let weight = p.get_weight();
-
-                    // all nodes of the shortest path (including source and target)
-                    // let nodes = p.get_nodes();
-
-                    if weight < distance as usize {
-                        println!("{:?}", (weight, node));
-                        safe = 1;
-                    }
-                }
-                None => {
-                    safe = 0;
-                }
-            }
-            if safe == 1 {
-                Some(node.clone())
-            } else {
-                None
-            }
+            // edge weights are times, so the length is measured along the route
+            let network_distance: f64 = route
+                .windows(2)
+                .map(|pair| {
+                    Location::new(pair[0].0, pair[0].1)
+                        .kilometers_to(&Location::new(pair[1].0, pair[1].1))
+                        * 1000.0
+                })
+                .sum();
+            Some(ReachableAmenity {
+                node: node.clone(),
+                network_distance,
+                walking_time,
+                straight_line_distance: origin_location.kilometers_to(&location) * 1000.0,
+                route: Some(route),
+            })
         })
         .collect();
 
-    return new_amenity_list;
+    new_amenity_list
 }
 
 fn trim_newline(s: &mut String) {
@@ -413,316 +1094,738 @@ fn trim_newline(s: &mut String) {
     }
 }
 
-fn get_input(buffer: &mut String) {
-    let _ = input().read_line(buffer);
+// Fails once stdin is closed rather than reading empty lines forever
+fn get_input(buffer: &mut String) -> Result<()> {
+    if input().read_line(buffer)? == 0 {
+        return Err(Error::InvalidInput(
+            "Input ended before every question was answered".to_string(),
+        ));
+    }
     trim_newline(buffer);
+    Ok(())
 }
 
-fn require_specific_input(arg: String, conditions: Vec<String>) -> String {
+fn require_specific_input(conditions: Vec<String>) -> Result<String> {
     let mut buffer = String::new();
     let mut done = false;
-    for condition in conditions.iter() {
-        if &arg == condition {
-            return arg;
-        }
-    }
     while !done {
         buffer = "".to_string();
-        get_input(&mut buffer);
+        get_input(&mut buffer)?;
         for condition in conditions.iter() {
             if &buffer == condition {
                 done = true;
             }
         }
     }
-    return buffer;
+    Ok(buffer)
 }
 
-fn get_poi_near_address(address: String, distance: u64) -> Vec<Node> {
-    let url = get_active_url();
-    let deltay: f64 = (distance as f64 / 111000.0).abs();
-    let coordinates = get_address_coordinates(address);
-    let deltax: f64 = (deltay / coordinates.0.to_radians().cos()).abs();
-    let response: Value = get_overpass_json_response(coordinates, deltay, deltax, url);
-    let (amenities, highways, highway_nodes, nodes_lut): (
-        Vec<Node>,
-        Vec<Way>,
-        HashMap<usize, Node>,
-        BiHashMap<usize, usize>,
-    ) = response_to_structures(response);
+fn prompt(message: &str) -> Result<String> {
+    println!("{}", message);
+    let mut buffer = String::new();
+    get_input(&mut buffer)?;
+    Ok(buffer)
+}
+
+// Nothing needs asking when --minutes already gives the limit, and an empty
+// answer keeps the default
+fn prompt_distance(minutes: Option<f64>) -> Result<Option<Distance>> {
+    if minutes.is_some() {
+        return Ok(None);
+    }
+    let answer = prompt("Please enter maximum distance in metres, or a time such as 15min")?;
+    if answer.trim().is_empty() {
+        return Ok(None);
+    }
+    parse_distance(&answer)
+        .map(Some)
+        .map_err(Error::InvalidInput)
+}
+
+fn get_poi(
+    source: &dyn MapDataSource,
+    coordinates: (f64, f64),
+    time_limit: f64,
+    profile: Profile,
+    isochrone: bool,
+    routes: bool,
+    dem_directory: Option<&Path>,
+) -> Result<QueryResult> {
+    let bounding_box = get_search_bounding_box(coordinates, profile.travel_distance(time_limit));
+    let dem: Vec<Grid> = dem_directory
+        .map(|directory| load_dem(directory, bounding_box))
+        .unwrap_or_default();
+    let (amenities, highways, _, _): MapData = source.get_map_data(bounding_box)?;
+    let (amenities, highways, highway_nodes, nodes_lut): MapData =
+        filter_map_data(amenities, highways, profile);
+    if highway_nodes.is_empty() {
+        return Err(Error::OriginNotSnappable(coordinates));
+    }
+    let node_coordinates = create_coordinate_table(&highway_nodes, &amenities, &nodes_lut);
     let (search_tree, entries): (ImmutableKdTree<f64, 2>, Vec<usize>) =
         create_kdtree(highway_nodes.clone());
-    let nearest: NearestNeighbour<f64, u64> =
-        search_tree.nearest_one::<SquaredEuclidean>(&[coordinates.0, coordinates.1]);
+    let (origin_node, origin_seconds) = snap_origin(
+        coordinates,
+        (&search_tree, &entries),
+        &highway_nodes,
+        profile,
+    )?;
+    if origin_seconds > time_limit {
+        return Ok((Vec::new(), None));
+    }
+    let origin_id = get_graph_id(origin_node, &nodes_lut);
     let path_graph = create_graph(
         amenities.clone(),
         highways,
         highway_nodes,
         nodes_lut.clone(),
-        search_tree,
-        entries,
+        (search_tree, entries),
+        profile,
+        &dem,
     );
-    let new_amentites = cull_amenities(amenities, path_graph, nearest.item, nodes_lut, distance);
-    return new_amentites.clone();
+    let limit = seconds_to_weight(time_limit - origin_seconds);
+    let (distances, predecessors) = shortest_path_tree(&path_graph, origin_id, limit);
+    let polygon = if isochrone {
+        let points = reachable_points(&path_graph, &distances, &node_coordinates, limit);
+        create_isochrone(&points)
+    } else {
+        None
+    };
+    // amenities the search never reached are out of range
+    let shortest_path = |graph_id: usize| {
+        let weight = *distances.get(&graph_id)?;
+        Some((weight, path_to(&predecessors, graph_id)))
+    };
+    let mut new_amenities = cull_amenities(
+        amenities,
+        shortest_path,
+        nodes_lut,
+        (coordinates, origin_seconds),
+        &node_coordinates,
+    );
+    if !routes {
+        for amenity in new_amenities.iter_mut() {
+            amenity.route = None;
+        }
+    }
+    Ok((new_amenities, polygon))
 }
 
-fn get_poi_from_cache(
-    city: String,
-    radius_string: String,
-    address: String,
-    distance: u64,
-) -> Vec<Node> {
-    let coordinates = get_address_coordinates(address.clone());
-    let (amenities, highways, highway_nodes, nodes_lut) =
-        cull_poi_cache(coordinates, radius_string, city, distance);
+// Like get_poi, but routes over a contraction hierarchy prepared for the whole
+// cache, so only the amenities near the origin need looking up
+fn get_poi_prepared(
+    source: &dyn MapDataSource,
+    coordinates: (f64, f64),
+    time_limit: f64,
+    profile: Profile,
+    routes: bool,
+    (fast_graph, nodes_lut): &PreparedGraph,
+) -> Result<Vec<ReachableAmenity>> {
+    let bounding_box = get_search_bounding_box(coordinates, profile.travel_distance(time_limit));
+    let (amenities, highways, _, _): MapData = source.get_map_data(bounding_box)?;
+    let (amenities, _, highway_nodes, _): MapData = filter_map_data(amenities, highways, profile);
+    if highway_nodes.is_empty() {
+        return Err(Error::OriginNotSnappable(coordinates));
+    }
+    let node_coordinates = create_coordinate_table(&highway_nodes, &amenities, nodes_lut);
     let (search_tree, entries): (ImmutableKdTree<f64, 2>, Vec<usize>) =
         create_kdtree(highway_nodes.clone());
-    let nearest: NearestNeighbour<f64, u64> =
-        search_tree.nearest_one::<SquaredEuclidean>(&[coordinates.0, coordinates.1]);
-    let path_graph = create_graph(
-        amenities.clone(),
-        highways,
-        highway_nodes,
+    let (origin_node, origin_seconds) = snap_origin(
+        coordinates,
+        (&search_tree, &entries),
+        &highway_nodes,
+        profile,
+    )?;
+    let origin_id = get_graph_id(origin_node, nodes_lut);
+    if origin_id >= fast_graph.get_num_nodes() {
+        return Err(Error::OriginNotSnappable(coordinates));
+    }
+    if origin_seconds > time_limit {
+        return Ok(Vec::new());
+    }
+    let limit = seconds_to_weight(time_limit - origin_seconds);
+    let mut calculator = fast_paths::create_calculator(fast_graph);
+    let shortest_path = |graph_id: usize| {
+        if graph_id >= fast_graph.get_num_nodes() {
+            return None;
+        }
+        let path = calculator.calc_path(fast_graph, origin_id, graph_id)?;
+        if path.get_weight() > limit {
+            return None;
+        }
+        Some((path.get_weight(), path.get_nodes().clone()))
+    };
+    let mut new_amenities = cull_amenities(
+        amenities,
+        shortest_path,
         nodes_lut.clone(),
-        search_tree,
-        entries,
+        (coordinates, origin_seconds),
+        &node_coordinates,
     );
-    let new_amentites = cull_amenities(amenities, path_graph, nearest.item, nodes_lut, distance);
-    return new_amentites.clone();
+    if !routes {
+        for amenity in new_amenities.iter_mut() {
+            amenity.route = None;
+        }
+    }
+    Ok(new_amenities)
 }
 
-fn write_poi_cache(radius_string: String, address: String) {
-    let url = get_active_url();
-    let deltay: f64 = radius_string.parse::<f64>().unwrap_or(10000.0) / 111000.0;
-    let coordinates = get_address_coordinates(address.clone());
-    let deltax: f64 = (deltay / coordinates.0.cos()).abs();
-    let response: Value = get_overpass_json_response(coordinates, deltay, deltax, url);
-    // println!("{}", response["version"]);
-    let (amenities, highways, highway_nodes, _): (
-        Vec<Node>,
-        Vec<Way>,
-        HashMap<usize, Node>,
-        BiHashMap<usize, usize>,
-    ) = response_to_structures(response);
-    // println!("{:?}", serde_json::to_string(&amenities));
+fn write_poi_cache(radius_string: String, address: String, compress: bool) -> Result<()> {
+    let coordinates = get_origin_coordinates(address.clone(), None)?;
     let bind = format!("./Cache/{}_{}", address, radius_string);
     let path = Path::new(&bind);
-    let _ = fs::create_dir_all(path);
-    let mut amenities_path = File::create(&format!(
-        "./Cache/{}_{}/amenities.json",
-        address, radius_string
-    ))
-    .unwrap();
-    let _ = write!(
-        &mut amenities_path,
-        "{}",
-        serde_json::to_string_pretty(&amenities).unwrap()
-    );
-    let mut highways_path = File::create(&format!(
-        "./Cache/{}_{}/highways.json",
-        address, radius_string
-    ))
-    .unwrap();
-    let _ = write!(
-        &mut highways_path,
-        "{}",
-        serde_json::to_string_pretty(&highways).unwrap()
-    );
-    let mut highway_nodes_path = File::create(&format!(
-        "./Cache/{}_{}/highway_nodes.json",
-        address, radius_string
-    ))
-    .unwrap();
-    let _ = write!(
-        &mut highway_nodes_path,
-        "{}",
-        serde_json::to_string_pretty(&highway_nodes).unwrap()
-    );
+    fs::create_dir_all(path)?;
+    remove_cache_graphs(path);
+    build_poi_cache(path, address, radius_string, coordinates, compress)
 }
 
-fn cull_poi_cache(
-    coordinates: (f64, f64),
+// Downloads everything within the radius of the coordinates into the
+// directory, along with its manifest
+fn build_poi_cache(
+    path: &Path,
+    address: String,
     radius_string: String,
-    city: String,
-    distance: u64,
-) -> (
-    Vec<Node>,
-    Vec<Way>,
-    HashMap<usize, Node>,
-    BiHashMap<usize, usize>,
-) {
-    let amenities_path = File::open(&format!(
-        "./Cache/{}_{}/amenities.json",
-        city, radius_string
-    ))
-    .unwrap();
-    let highways_path =
-        File::open(&format!("./Cache/{}_{}/highways.json", city, radius_string)).unwrap();
-    let highway_nodes_path = File::open(&format!(
-        "./Cache/{}_{}/highway_nodes.json",
-        city, radius_string
-    ))
-    .unwrap();
-    let buffered = BufReader::new(amenities_path);
-    let amenities: Vec<Node> = serde_json::from_reader(buffered).unwrap();
-    let buffered2 = BufReader::new(highways_path);
-    let highways: Vec<Way> = serde_json::from_reader(buffered2).unwrap();
-    let buffered3 = BufReader::new(highway_nodes_path);
-    let highway_nodes: HashMap<usize, Node> = serde_json::from_reader(buffered3).unwrap();
-    let new_amenities: Vec<Node> = amenities
-        .iter()
-        .filter_map(|amenity: &Node| {
-            let start: Location = Location::new(
-                f64::from_bits(amenity.coordinate.0),
-                f64::from_bits(amenity.coordinate.1),
-            );
-            let end: Location = Location::new(coordinates.0, coordinates.1);
-            if (start.kilometers_to(&end) * 1000.0) < distance as f64 {
-                Some(amenity.clone())
-            } else {
-                None
-            }
-        })
-        .collect();
-    let close_highway_nodes: HashMap<usize, Node> = highway_nodes
-        .par_iter()
-        .filter_map(|highway_node: (&usize, &Node)| {
-            let start: Location = Location::new(
-                f64::from_bits(highway_node.1.coordinate.0),
-                f64::from_bits(highway_node.1.coordinate.1),
-            );
-            let end: Location = Location::new(coordinates.0, coordinates.1);
+    coordinates: (f64, f64),
+    compress: bool,
+) -> Result<()> {
+    let url = get_active_url()?;
+    let radius = parse_radius(&radius_string).map_err(Error::InvalidInput)?;
+    let bounding_box = get_search_bounding_box(coordinates, radius as f64);
+    let response: Value = get_overpass_json_response(bounding_box, url, true, &ALL_MODES)?;
+    // println!("{}", response["version"]);
+    let manifest = Manifest {
+        city: address.clone(),
+        radius: radius_string.clone(),
+        centre: coordinates,
+        bounding_box,
+        osm_base: response["osm3s"]["timestamp_osm_base"]
+            .as_str()
+            .map(|timestamp| timestamp.to_string()),
+        query_hash: query_hash(&get_overpass_query("{bbox}", true, &ALL_MODES)),
+        format_version: CACHE_VERSION,
+    };
+    let addresses: Vec<Address> = response_to_addresses(&response);
+    let (amenities, highways, _, _): MapData = response_to_structures(response)?;
+    // println!("{:?}", serde_json::to_string(&amenities));
+    write_binary_cache(path, &amenities, &highways, &addresses, compress)?;
+    write_manifest(path, &manifest)?;
+    // most searches use the default profile, so its graph is ready from the start
+    if let Some(profile) = Profile::from_name(DEFAULT_PROFILE) {
+        println!("Preparing routing graph...");
+        prepare_cache_graph(path, amenities, highways, profile)?;
+    }
+    Ok(())
+}
 
-            if (start.kilometers_to(&end) * 1000.0) < distance as f64 {
-                Some((highway_node.0.clone(), highway_node.1.clone()))
-            } else {
-                None
-            }
-        })
-        .collect();
+// Caches written before the address index existed have no addresses
+fn read_address_cache(city: String, radius_string: String) -> Option<Vec<Address>> {
+    let bind = format!("./Cache/{}_{}", city, radius_string);
+    let path = Path::new(&bind);
+    read_binary_addresses(path).or_else(|| read_json_addresses(path))
+}
 
-    let mut new_highway_nodes: HashMap<usize, Node> = HashMap::new();
+// Falls back to the JSON files of caches written before the binary format
+fn read_poi_cache(city: String, radius_string: String) -> Result<(Vec<Node>, Vec<Way>)> {
+    let bind = format!("./Cache/{}_{}", city, radius_string);
+    let path = Path::new(&bind);
+    if !cache_exists(path) {
+        return Err(Error::CacheMissing(bind));
+    }
+    read_binary_cache(path)
+        .or_else(|| read_json_cache(path))
+        .ok_or(Error::CacheCorrupt(bind))
+}
 
-    let new_highways: Vec<Way> = highways
+// The travel time limit in seconds, from --minutes or from the distance,
+// 1500 metres when neither is given
+fn get_time_limit(distance: Option<Distance>, minutes: Option<f64>, profile: Profile) -> f64 {
+    if let Some(minutes) = minutes {
+        return minutes * 60.0;
+    }
+    match distance.unwrap_or(Distance::Metres(1500.0)) {
+        Distance::Minutes(minutes) => minutes * 60.0,
+        Distance::Metres(metres) => profile.travel_time(metres),
+    }
+}
+
+// Orders the amenities by one of their distances, nearest first
+fn sort_amenities(amenities: &mut [ReachableAmenity], sort: Sort) {
+    amenities.sort_by(|a, b| match sort {
+        Sort::NetworkDistance => a.network_distance.total_cmp(&b.network_distance),
+        Sort::WalkingTime => a.walking_time.total_cmp(&b.walking_time),
+        Sort::StraightLineDistance => a
+            .straight_line_distance
+            .total_cmp(&b.straight_line_distance),
+    });
+}
+
+fn write_output(
+    origin: (f64, f64),
+    amenities: &[ReachableAmenity],
+    format: Option<Format>,
+    output: Option<&Path>,
+) -> Result<()> {
+    let nodes: Vec<Node> = amenities
         .iter()
-        .filter_map(|highway: &Way| {
-            let mut valid = false;
-            for node in highway.nodes.iter() {
-                if close_highway_nodes.contains_key(&node.id) {
-                    valid = true;
-                }
-            }
-            if valid {
-                for node in highway.nodes.iter() {
-                    new_highway_nodes.insert(node.id.clone(), node.clone());
-                }
-                Some(highway.clone())
-            } else {
-                None
-            }
-        })
+        .map(|amenity| amenity.node.clone())
         .collect();
+    let (default_path, contents) = if format == Some(Format::Geojson) {
+        (
+            "./nearby_poi.geojson",
+            serde_json::to_string_pretty(&amenities_to_geojson(origin, amenities)).unwrap(),
+        )
+    } else {
+        (
+            "./nearby_poi.json",
+            serde_json::to_string_pretty(&amenities).unwrap(),
+        )
+    };
+    let mut amenities_path = File::create(output.unwrap_or(Path::new(default_path)))?;
+    write!(&mut amenities_path, "{}", contents)?;
+    print_completeness_report(&nodes);
+    Ok(())
+}
 
-    let mut adder_index: usize = 0;
-    let mut nodes_lookup_table: BiHashMap<usize, usize> = BiMap::new();
-    for value in new_highway_nodes.iter() {
-        nodes_lookup_table.insert(adder_index, value.1.id);
-        adder_index += 1;
+// The cache as a map data source, with its contraction hierarchy for the
+// profile when prepared graphs can be used. The hierarchy leaves out
+// elevation and can't sweep out an isochrone, so those fall back to searching
+// the raw graph.
+fn open_cache(
+    city: String,
+    radius_string: String,
+    profile: Profile,
+    use_prepared: bool,
+) -> Result<(Box<dyn MapDataSource>, Option<PreparedGraph>)> {
+    let prepared = if use_prepared {
+        Some(cache_graph(&city, &radius_string, profile)?)
+    } else {
+        None
+    };
+    Ok((Box::new(CacheSource::new(city, radius_string)), prepared))
+}
+
+// The cache's saved hierarchy for the profile, contracting it the first time
+fn cache_graph(city: &str, radius_string: &str, profile: Profile) -> Result<PreparedGraph> {
+    let string_path = format!("./Cache/{}_{}", city, radius_string);
+    if let Some(prepared) = load_cache_graph(Path::new(&string_path), profile) {
+        return Ok(prepared);
     }
-    for node in new_amenities.iter() {
-        nodes_lookup_table.insert(adder_index, node.id);
-        adder_index += 1;
+    println!("Preparing routing graph for this profile...");
+    let (amenities, highways) = read_poi_cache(city.to_string(), radius_string.to_string())?;
+    prepare_cache_graph(Path::new(&string_path), amenities, highways, profile)
+}
+
+// Warns when the search would reach past the edge of the cache, or when the
+// cache was fetched with a different query than the current one
+fn warn_about_cache(manifest: &Manifest, search_box: BoundingBox) {
+    if !covers(manifest.bounding_box, search_box) {
+        println!(
+            "Warning: the search area reaches outside cache {}_{}, amenities beyond its edge will be missing",
+            manifest.city, manifest.radius
+        );
     }
-    return (
-        new_amenities,
-        new_highways,
-        new_highway_nodes,
-        nodes_lookup_table,
-    );
+    if manifest.query_hash != query_hash(&get_overpass_query("{bbox}", true, &ALL_MODES)) {
+        println!(
+            "Warning: cache {}_{} was fetched with an older query and may be missing features, consider rebuilding it",
+            manifest.city, manifest.radius
+        );
+    }
+}
+
+// Overpass, the tile cache or a local file, whichever the options ask for
+fn query_source(
+    file: Option<PathBuf>,
+    tiles: bool,
+    compress: bool,
+    mode: Mode,
+) -> Result<Box<dyn MapDataSource>> {
+    Ok(match file {
+        Some(path) => source::osm_file_source(path),
+        None if tiles => Box::new(TileSource::new(compress)),
+        None => Box::new(OverpassSource::new(get_active_url()?, mode)),
+    })
+}
+
+// Downloads the cache if it hasn't been built yet
+fn ensure_cache(city: &str, radius_string: &str, compress: bool) -> Result<()> {
+    let string_path = format!("./Cache/{}_{}", city, radius_string);
+    println!("Looking for cache...");
+    if !cache_exists(Path::new(&string_path)) {
+        println!("No Cache, creating...");
+        write_poi_cache(radius_string.to_string(), city.to_string(), compress)?;
+    }
+    println!("Cache Found!");
+    Ok(())
+}
+
+// A source to search, the origin resolved against it, and its contraction
+// hierarchy when one can be used
+type OpenedSource = (Box<dyn MapDataSource>, (f64, f64), Option<PreparedGraph>);
+
+// Opens the named cache, looking the origin up in its address index first
+fn open_city_cache(
+    city: String,
+    radius_string: String,
+    origin: String,
+    time_limit: f64,
+    profile: Profile,
+    use_prepared: bool,
+) -> Result<OpenedSource> {
+    let addresses = read_address_cache(city.clone(), radius_string.clone());
+    let coordinates = get_origin_coordinates(origin, addresses.as_deref())?;
+    let search_box = get_search_bounding_box(coordinates, profile.travel_distance(time_limit));
+    let string_path = format!("./Cache/{}_{}", city, radius_string);
+    if let Some(manifest) = read_manifest(Path::new(&string_path)) {
+        warn_about_cache(&manifest, search_box);
+    }
+    let (source, prepared) = open_cache(city, radius_string, profile, use_prepared)?;
+    Ok((source, coordinates, prepared))
+}
+
+// Opens whichever cache covers the search around the origin
+fn open_covering_cache(
+    origin: String,
+    time_limit: f64,
+    profile: Profile,
+    use_prepared: bool,
+) -> Result<OpenedSource> {
+    let cache_root = Path::new("./Cache");
+    // Any cache's address index may hold the address, not just the one
+    // that ends up being searched
+    let coordinates = match list_manifests(cache_root).iter().find_map(|(_, manifest)| {
+        let addresses = read_address_cache(manifest.city.clone(), manifest.radius.clone())?;
+        find_address(&addresses, &origin)
+    }) {
+        Some(coordinates) => coordinates,
+        None => get_origin_coordinates(origin, None)?,
+    };
+    let search_box = get_search_bounding_box(coordinates, profile.travel_distance(time_limit));
+    let manifest = match find_covering_cache(cache_root, coordinates, search_box) {
+        Some((manifest, _)) => manifest,
+        None => {
+            return Err(Error::CacheMissing(format!(
+                "{} covering {:?}",
+                cache_root.display(),
+                coordinates
+            )))
+        }
+    };
+    println!("Using cache {}_{}", manifest.city, manifest.radius);
+    warn_about_cache(&manifest, search_box);
+    let (source, prepared) = open_cache(manifest.city, manifest.radius, profile, use_prepared)?;
+    Ok((source, coordinates, prepared))
+}
+
+// Runs the search and writes the results, and the isochrone when asked for
+fn search_and_write(
+    args: &Cli,
+    profile: Profile,
+    source: &dyn MapDataSource,
+    coordinates: (f64, f64),
+    time_limit: f64,
+    prepared: Option<&PreparedGraph>,
+) -> Result<()> {
+    let (mut amenities, isochrone) = match prepared {
+        Some(prepared) => (
+            get_poi_prepared(
+                source,
+                coordinates,
+                time_limit,
+                profile,
+                args.routes,
+                prepared,
+            )?,
+            None,
+        ),
+        None => get_poi(
+            source,
+            coordinates,
+            time_limit,
+            profile,
+            args.isochrone.is_some(),
+            args.routes,
+            args.dem.as_deref(),
+        )?,
+    };
+    sort_amenities(&mut amenities, args.sort);
+    write_output(coordinates, &amenities, args.format, args.output.as_deref())?;
+    if let (Some(isochrone_path), Some(polygon)) = (&args.isochrone, isochrone) {
+        write_isochrone(isochrone_path.clone(), coordinates, &polygon, time_limit)?;
+    }
+    Ok(())
 }
 
 fn main() {
-    let args = Cli::parse();
-    let message = concat!(
-        "Welcome to the point of interest searcher!\n",
-        "Please enter 1 for searching online, or 2 for searching with cache!\n"
-    );
-    print!("{}", message);
-    let buffer = require_specific_input(
-        args.arg1.unwrap_or("".to_string()),
-        vec!["1".to_string(), "2".to_string()],
-    );
-    if buffer == "1".to_string() {
-        let mut address = String::new();
-        let mut distance = String::new();
-        println!("Please enter an Address");
-        if args.arg2.clone().unwrap_or("".to_string()) != "" {
-            address = args.arg2.unwrap();
-        } else {
-            get_input(&mut address);
+    if let Err(error) = run(Cli::parse()) {
+        eprintln!("Error: {}", error);
+        process::exit(error.exit_code());
+    }
+}
+
+fn run(args: Cli) -> Result<()> {
+    // ascii grids only make sense for heatmaps, and heatmaps aren't plain json
+    match (&args.command, args.format) {
+        (Some(Command::Heatmap { .. }), Some(Format::Json)) => {
+            return Err(Error::InvalidInput(
+                "Heatmaps are written as geojson or ascii".to_string(),
+            ))
         }
-        println!("Please enter maximum distance");
-        if args.arg3.clone().unwrap_or("".to_string()) != "" {
-            distance = args.arg3.unwrap()
-        } else {
-            get_input(&mut distance);
+        (Some(Command::Heatmap { .. }), _) => {}
+        (_, Some(Format::Ascii)) => {
+            return Err(Error::InvalidInput(
+                "Only heatmaps can be written as ascii, use json or geojson".to_string(),
+            ))
         }
-
-        let amenities = get_poi_near_address(address, distance.parse::<u64>().unwrap_or(1500));
-        let mut amenities_path = File::create(&format!("./nearby_poi.json")).unwrap();
-        let _ = write!(
-            &mut amenities_path,
-            "{}",
-            serde_json::to_string_pretty(&amenities).unwrap()
-        );
-    } else if buffer == "2".to_string() {
-        let mut city = String::new();
-        let mut radius_string = String::new();
-        println!("Please enter a City Name");
-        if args.arg2.clone().unwrap_or("".to_string()) != "" {
-            city = args.arg2.unwrap()
-        } else {
-            get_input(&mut city);
+        _ => {}
+    }
+    let profile = Profile::from_name(&args.profile).ok_or(Error::InvalidInput(format!(
+        "Unknown profile {}, use adult, elderly, child, cycling, wheelchair or a speed in m/s",
+        args.profile
+    )))?;
+    let compress = !args.no_compression;
+    // The hierarchy leaves out elevation and can't sweep out an isochrone
+    let use_prepared = args.dem.is_none() && args.isochrone.is_none();
+    let action = match &args.command {
+        // scripts and cron jobs can't answer the menu, so stop rather than hang
+        None if args.non_interactive || !input().is_terminal() => {
+            return Err(Error::InvalidInput(
+                "No command given and not running interactively, use `query` or `cache query` with --address or --lat and --lon, see --help".to_string(),
+            ))
         }
-        println!("Please enter a node collection radius in metres");
-        if args.arg3.clone().unwrap_or("".to_string()) != "" {
-            radius_string = args.arg3.unwrap()
-        } else {
-            get_input(&mut radius_string);
+        None => return run_interactive(&args, profile),
+        Some(Command::Query { search, file }) => {
+            let time_limit = get_time_limit(search.distance, args.minutes, profile);
+            let source = query_source(file.clone(), args.tiles, compress, profile.mode)?;
+            let coordinates = get_origin_coordinates(search.origin(), None)?;
+            return search_and_write(
+                &args,
+                profile,
+                source.as_ref(),
+                coordinates,
+                time_limit,
+                None,
+            );
         }
-        let string_path = format!("./Cache/{}_{}/amenities.json", city, radius_string);
-        let path = Path::new(&string_path);
-        println!("Looking for cache...");
-        if !path.exists() {
-            println!("No Cache, creating...");
-            write_poi_cache(radius_string.clone(), city.clone());
+        Some(Command::Batch {
+            input,
+            city,
+            radius,
+            distance,
+        }) => {
+            let time_limit = get_time_limit(*distance, args.minutes, profile);
+            let radius = radius.to_string();
+            ensure_cache(city, &radius, compress)?;
+            // read once and shared by every search
+            let (amenities, highways) = read_poi_cache(city.clone(), radius.clone())?;
+            let source = InMemorySource::new(amenities, highways);
+            let prepared = if args.dem.is_none() {
+                Some(cache_graph(city, &radius, profile)?)
+            } else {
+                None
+            };
+            let addresses = read_address_cache(city.clone(), radius);
+            let search = |coordinates: (f64, f64)| match &prepared {
+                Some(prepared) => {
+                    get_poi_prepared(&source, coordinates, time_limit, profile, false, prepared)
+                }
+                None => get_poi(
+                    &source,
+                    coordinates,
+                    time_limit,
+                    profile,
+                    false,
+                    false,
+                    args.dem.as_deref(),
+                )
+                .map(|(amenities, _)| amenities),
+            };
+            let output = args.output.clone().unwrap_or(PathBuf::from("./batch.csv"));
+            return run_batch(input, &output, addresses.as_deref(), search);
         }
-        println!("Cache Found!");
-        let mut address = String::new();
-        let mut distance = String::new();
-        println!("Please enter an Address");
-        if args.arg4.clone().unwrap_or("".to_string()) != "" {
-            address = args.arg4.unwrap()
-        } else {
-            get_input(&mut address);
+        Some(Command::Heatmap {
+            city,
+            radius,
+            cell_size,
+            distance,
+        }) => {
+            let time_limit = get_time_limit(
+                distance.or(Some(Distance::Minutes(15.0))),
+                args.minutes,
+                profile,
+            );
+            return run_heatmap(
+                &args,
+                city,
+                &radius.to_string(),
+                *cell_size,
+                time_limit,
+                profile,
+            );
         }
-        println!("Please enter maximum distance");
-        if args.arg5.clone().unwrap_or("".to_string()) != "" {
-            distance = args.arg5.unwrap()
-        } else {
-            get_input(&mut distance);
+        Some(Command::Cache { action }) => action,
+    };
+    match action {
+        CacheAction::Build { city, radius } => {
+            write_poi_cache(radius.to_string(), city.clone(), compress)
+        }
+        CacheAction::Query {
+            search,
+            city,
+            radius,
+        } => {
+            let time_limit = get_time_limit(search.distance, args.minutes, profile);
+            let (source, coordinates, prepared) = match (city, radius) {
+                (Some(city), Some(radius)) => {
+                    let radius = radius.to_string();
+                    ensure_cache(city, &radius, compress)?;
+                    open_city_cache(
+                        city.clone(),
+                        radius,
+                        search.origin(),
+                        time_limit,
+                        profile,
+                        use_prepared,
+                    )?
+                }
+                _ => open_covering_cache(search.origin(), time_limit, profile, use_prepared)?,
+            };
+            search_and_write(
+                &args,
+                profile,
+                source.as_ref(),
+                coordinates,
+                time_limit,
+                prepared.as_ref(),
+            )
+        }
+        CacheAction::List => {
+            list_caches();
+            Ok(())
         }
-        let amenities = get_poi_from_cache(
+        CacheAction::Inspect { name } => inspect_cache(name),
+        CacheAction::Convert { directory } => convert_json_cache(directory, compress),
+        CacheAction::Refresh { name } => refresh_cache(name, compress),
+        CacheAction::Purge { older_than } => {
+            purge_caches(*older_than);
+            Ok(())
+        }
+    }
+}
+
+// Sweeps a grid over the cache's bounding box and writes the categories each
+// cell can reach
+fn run_heatmap(
+    args: &Cli,
+    city: &str,
+    radius_string: &str,
+    cell_size: f64,
+    time_limit: f64,
+    profile: Profile,
+) -> Result<()> {
+    if !cell_size.is_finite() || cell_size <= 0.0 {
+        return Err(Error::InvalidInput(
+            "The cell size must be a number of metres above 0".to_string(),
+        ));
+    }
+    ensure_cache(city, radius_string, !args.no_compression)?;
+    let (amenities, highways) = read_poi_cache(city.to_string(), radius_string.to_string())?;
+    let string_path = format!("./Cache/{}_{}", city, radius_string);
+    let bounding_box = match read_manifest(Path::new(&string_path)) {
+        Some(manifest) => manifest.bounding_box,
+        None => extent(&highways),
+    };
+    let dem: Vec<Grid> = args
+        .dem
+        .as_deref()
+        .map(|directory| load_dem(directory, bounding_box))
+        .unwrap_or_default();
+    let cells = Cells::new(bounding_box, cell_size)?;
+    let coverage = coverage(amenities, highways, &cells, time_limit, profile, &dem)?;
+    if args.format == Some(Format::Ascii) {
+        let output = args
+            .output
+            .clone()
+            .unwrap_or(PathBuf::from("./heatmap.asc"));
+        write_heatmap_ascii(&output, &cells, &coverage)
+    } else {
+        let output = args
+            .output
+            .clone()
+            .unwrap_or(PathBuf::from("./heatmap.geojson"));
+        write_heatmap_geojson(&output, &cells, &coverage, time_limit)
+    }
+}
+
+// The menu the searcher started out with, asking for everything on stdin
+fn run_interactive(args: &Cli, profile: Profile) -> Result<()> {
+    let message = concat!(
+        "Welcome to the point of interest searcher!\n",
+        "Please enter 1 for searching online, 2 for searching with cache,\n",
+        "3 for searching a local .osm.pbf or .osm file,\n",
+        "or 4 for searching whichever cache covers an address!\n"
+    );
+    print!("{}", message);
+    let buffer = require_specific_input(vec![
+        "1".to_string(),
+        "2".to_string(),
+        "3".to_string(),
+        "4".to_string(),
+    ])?;
+    let compress = !args.no_compression;
+    let use_prepared = args.dem.is_none() && args.isochrone.is_none();
+    let (source, coordinates, time_limit, prepared): (
+        Box<dyn MapDataSource>,
+        (f64, f64),
+        f64,
+        Option<PreparedGraph>,
+    ) = if buffer == "2" {
+        let city = prompt("Please enter a City Name or lat,lon coordinates")?;
+        let radius_string = prompt("Please enter a node collection radius in metres")?;
+        let radius_string = parse_radius(&radius_string)
+            .map_err(Error::InvalidInput)?
+            .to_string();
+        ensure_cache(&city, &radius_string, compress)?;
+        let address = prompt("Please enter an Address or lat,lon coordinates")?;
+        let time_limit = get_time_limit(prompt_distance(args.minutes)?, args.minutes, profile);
+        let (source, coordinates, prepared) = open_city_cache(
             city,
             radius_string,
             address,
-            distance.parse::<u64>().unwrap_or(1500),
-        );
-        let mut amenities_path = File::create(&format!("./nearby_poi.json")).unwrap();
-        let _ = write!(
-            &mut amenities_path,
-            "{}",
-            serde_json::to_string_pretty(&amenities).unwrap()
-        );
-    }
+            time_limit,
+            profile,
+            use_prepared,
+        )?;
+        (source, coordinates, time_limit, prepared)
+    } else if buffer == "4" {
+        let address = prompt("Please enter an Address or lat,lon coordinates")?;
+        let time_limit = get_time_limit(prompt_distance(args.minutes)?, args.minutes, profile);
+        let (source, coordinates, prepared) =
+            open_covering_cache(address, time_limit, profile, use_prepared)?;
+        (source, coordinates, time_limit, prepared)
+    } else {
+        let file = if buffer == "3" {
+            Some(PathBuf::from(prompt(
+                "Please enter the path to a .osm.pbf or .osm file",
+            )?))
+        } else {
+            None
+        };
+        let address = prompt("Please enter an Address or lat,lon coordinates")?;
+        let time_limit = get_time_limit(prompt_distance(args.minutes)?, args.minutes, profile);
+        let source = query_source(file, args.tiles, compress, profile.mode)?;
+        (
+            source,
+            get_origin_coordinates(address, None)?,
+            time_limit,
+            None,
+        )
+    };
+    search_and_write(
+        args,
+        profile,
+        source.as_ref(),
+        coordinates,
+        time_limit,
+        prepared.as_ref(),
+    )
 }
@@ -0,0 +1,244 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::cache::{
+    cache_exists, read_binary_addresses, read_binary_cache, read_json_addresses, read_json_cache,
+    read_manifest, MAP_FILE,
+};
+use crate::error::{Error, Result};
+use crate::tiles::TILE_ZOOM;
+use crate::{build_poi_cache, get_origin_coordinates};
+
+const CACHE_ROOT: &str = "./Cache";
+
+fn directory_size(path: &Path) -> u64 {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => directory_size(&entry.path()),
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+fn format_size(bytes: u64) -> String {
+    let units = ["B", "KB", "MB", "GB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, units[unit])
+}
+
+// Days since the cache's data was last written
+fn age_days(directory: &Path) -> Option<f64> {
+    let data = [MAP_FILE, "amenities.json"]
+        .iter()
+        .map(|name| directory.join(name))
+        .find(|path| path.exists())?;
+    let modified = fs::metadata(data).ok()?.modified().ok()?;
+    let age = SystemTime::now().duration_since(modified).ok()?;
+    Some(age.as_secs_f64() / 86400.0)
+}
+
+// City caches directly under ./Cache. Folders starting with a dot are
+// refreshes in progress.
+fn cache_directories() -> Vec<PathBuf> {
+    let mut directories: Vec<PathBuf> = match fs::read_dir(CACHE_ROOT) {
+        Ok(entries) => entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| {
+                !path
+                    .file_name()
+                    .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+                    && cache_exists(path)
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    directories.sort();
+    directories
+}
+
+fn tile_directories() -> Vec<PathBuf> {
+    match fs::read_dir(format!("{}/tiles/{}", CACHE_ROOT, TILE_ZOOM)) {
+        Ok(entries) => entries.flatten().map(|entry| entry.path()).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn cache_name(directory: &Path) -> String {
+    directory
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+pub fn list_caches() {
+    let directories = cache_directories();
+    if directories.is_empty() {
+        println!("No caches in {}", CACHE_ROOT);
+    }
+    for directory in directories.iter() {
+        let coverage = match read_manifest(directory) {
+            Some(manifest) => format!(
+                "({:.4}, {:.4}) to ({:.4}, {:.4})",
+                manifest.bounding_box.0,
+                manifest.bounding_box.1,
+                manifest.bounding_box.2,
+                manifest.bounding_box.3
+            ),
+            None => "unknown, no manifest".to_string(),
+        };
+        let age = age_days(directory)
+            .map(|age| format!("{:.1} days old", age))
+            .unwrap_or_else(|| "unknown age".to_string());
+        println!(
+            "{}  {}  {}  {}",
+            cache_name(directory),
+            format_size(directory_size(directory)),
+            coverage,
+            age
+        );
+    }
+    let tiles = tile_directories();
+    if !tiles.is_empty() {
+        let size: u64 = tiles.iter().map(|tile| directory_size(tile)).sum();
+        println!(
+            "tiles  {} zoom {} tiles  {}",
+            tiles.len(),
+            TILE_ZOOM,
+            format_size(size)
+        );
+    }
+}
+
+pub fn inspect_cache(name: &str) -> Result<()> {
+    let directory = Path::new(CACHE_ROOT).join(name);
+    if !cache_exists(&directory) {
+        return Err(Error::CacheMissing(directory.display().to_string()));
+    }
+    let (amenities, highways) = read_binary_cache(&directory)
+        .or_else(|| read_json_cache(&directory))
+        .ok_or_else(|| Error::CacheCorrupt(directory.display().to_string()))?;
+    let addresses = read_binary_addresses(&directory)
+        .or_else(|| read_json_addresses(&directory))
+        .unwrap_or_default();
+    let nodes: HashSet<usize> = highways
+        .iter()
+        .flat_map(|highway| highway.nodes.iter().map(|node| node.id))
+        .collect();
+    let mut categories: BTreeMap<String, usize> = BTreeMap::new();
+    for amenity in amenities.iter() {
+        let category = amenity
+            .category
+            .map(|category| format!("{:?}", category))
+            .unwrap_or_else(|| "Uncategorised".to_string());
+        *categories.entry(category).or_insert(0) += 1;
+    }
+    println!("Cache: {}", name);
+    println!("Size: {}", format_size(directory_size(&directory)));
+    if let Some(age) = age_days(&directory) {
+        println!("Age: {:.1} days", age);
+    }
+    if let Some(manifest) = read_manifest(&directory) {
+        println!("Centre: {:?}", manifest.centre);
+        println!("Bounding box: {:?}", manifest.bounding_box);
+        println!("Radius: {} m", manifest.radius);
+        println!(
+            "OSM data from: {}",
+            manifest.osm_base.as_deref().unwrap_or("unknown")
+        );
+        println!("Query hash: {}", manifest.query_hash);
+    }
+    println!("Amenities: {}", amenities.len());
+    for (category, count) in categories.iter() {
+        println!("    {}: {}", category, count);
+    }
+    println!("Ways: {}", highways.len());
+    println!("Nodes: {}", nodes.len());
+    println!("Addresses: {}", addresses.len());
+    Ok(())
+}
+
+// Downloads the cache again into a sibling folder and only swaps it in once
+// it has been written and reads back, so a failed download leaves the old
+// cache as it was
+pub fn refresh_cache(name: &str, compress: bool) -> Result<()> {
+    let root = Path::new(CACHE_ROOT);
+    let directory = root.join(name);
+    if !cache_exists(&directory) {
+        return Err(Error::CacheMissing(directory.display().to_string()));
+    }
+    // Caches from before manifests only have their folder name to go on
+    let (city, radius_string, centre) = match read_manifest(&directory) {
+        Some(manifest) => (manifest.city, manifest.radius, Some(manifest.centre)),
+        None => match name.rsplit_once('_') {
+            Some((city, radius)) => (city.to_string(), radius.to_string(), None),
+            None => {
+                return Err(Error::InvalidInput(format!(
+                    "Can't tell the city and radius of {}",
+                    name
+                )))
+            }
+        },
+    };
+    let coordinates = match centre {
+        Some(centre) => centre,
+        None => get_origin_coordinates(city.clone(), None)?,
+    };
+    let refreshed = root.join(format!(".{}.refresh", name));
+    let replaced = root.join(format!(".{}.old", name));
+    let _ = fs::remove_dir_all(&refreshed);
+    fs::create_dir_all(&refreshed)?;
+    if let Err(error) = build_poi_cache(&refreshed, city, radius_string, coordinates, compress) {
+        println!("Refreshing {} failed, keeping the old cache", name);
+        let _ = fs::remove_dir_all(&refreshed);
+        return Err(error);
+    }
+    if read_binary_cache(&refreshed).is_none() || read_manifest(&refreshed).is_none() {
+        println!("Refreshing {} failed, keeping the old cache", name);
+        let _ = fs::remove_dir_all(&refreshed);
+        return Err(Error::CacheCorrupt(refreshed.display().to_string()));
+    }
+    let _ = fs::remove_dir_all(&replaced);
+    fs::rename(&directory, &replaced)?;
+    if let Err(error) = fs::rename(&refreshed, &directory) {
+        println!("Refreshing {} failed, keeping the old cache", name);
+        fs::rename(&replaced, &directory)?;
+        let _ = fs::remove_dir_all(&refreshed);
+        return Err(error.into());
+    }
+    let _ = fs::remove_dir_all(&replaced);
+    println!("Refreshed {}", name);
+    Ok(())
+}
+
+// Deletes every cache and tile whose data is older than the given age
+pub fn purge_caches(older_than_days: f64) {
+    let mut removed = 0;
+    for directory in cache_directories().into_iter().chain(tile_directories()) {
+        let age = match age_days(&directory) {
+            Some(age) if age > older_than_days => age,
+            _ => continue,
+        };
+        if fs::remove_dir_all(&directory).is_ok() {
+            println!("Removed {} ({:.1} days old)", directory.display(), age);
+            removed += 1;
+        }
+    }
+    println!(
+        "Removed {} caches older than {} days",
+        removed, older_than_days
+    );
+}
@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use serde_json::{json, Value};
+
+use crate::error::{Error, Result};
+use crate::{in_bounding_box, is_selected_element, node_element, way_element, BoundingBox};
+
+fn element_tags(element: &roxmltree::Node) -> HashMap<String, String> {
+    element
+        .children()
+        .filter(|child| child.has_tag_name("tag"))
+        .filter_map(|tag| {
+            Some((
+                tag.attribute("k")?.to_string(),
+                tag.attribute("v")?.to_string(),
+            ))
+        })
+        .collect()
+}
+
+// JOSM keeps deleted objects in the file until upload, marked with an action
+fn is_deleted(element: &roxmltree::Node) -> bool {
+    element.attribute("action") == Some("delete") || element.attribute("visible") == Some("false")
+}
+
+// Objects created in JOSM carry negative ids. Reinterpreting them as u64 keeps
+// them unique and out of the range of real OSM ids.
+fn element_id(element: &roxmltree::Node) -> Option<u64> {
+    Some(element.attribute("id")?.parse::<i64>().ok()? as u64)
+}
+
+// Reads an OSM XML file, as exported by JOSM or the OSM API, and returns the
+// elements inside the bounding box in the same shape as an Overpass
+// `out geom` response, so it can be fed straight into `response_to_structures`.
+pub fn get_osm_xml_json_response(path: &Path, bounding_box: BoundingBox) -> Result<Value> {
+    let text = fs::read_to_string(path)
+        .map_err(|error| Error::InvalidMapFile(format!("{}: {}", path.display(), error)))?;
+    let document = roxmltree::Document::parse(&text)
+        .map_err(|error| Error::InvalidMapFile(format!("{}: {}", path.display(), error)))?;
+    let elements: Vec<roxmltree::Node> = document
+        .root_element()
+        .children()
+        .filter(|element| element.is_element() && !is_deleted(element))
+        .collect();
+
+    let mut node_coordinates: HashMap<u64, (f64, f64)> = HashMap::new();
+    for element in elements
+        .iter()
+        .filter(|element| element.has_tag_name("node"))
+    {
+        let lat = element
+            .attribute("lat")
+            .and_then(|lat| lat.parse::<f64>().ok());
+        let lon = element
+            .attribute("lon")
+            .and_then(|lon| lon.parse::<f64>().ok());
+        if let (Some(id), Some(lat), Some(lon)) = (element_id(element), lat, lon) {
+            node_coordinates.insert(id, (lat, lon));
+        }
+    }
+
+    let mut json_elements: Vec<Value> = Vec::new();
+    for element in elements.iter() {
+        let id = match element_id(element) {
+            Some(id) => id,
+            None => continue,
+        };
+        let tags = element_tags(element);
+        if element.has_tag_name("node") {
+            let coordinates = match node_coordinates.get(&id) {
+                Some(coordinates) => *coordinates,
+                None => continue,
+            };
+            if in_bounding_box(coordinates, bounding_box) && is_selected_element("node", &tags) {
+                json_elements.push(node_element(id, coordinates, &tags));
+            }
+        } else if element.has_tag_name("way") && is_selected_element("way", &tags) {
+            let nodes: Vec<(u64, (f64, f64))> = element
+                .children()
+                .filter(|child| child.has_tag_name("nd"))
+                .filter_map(|nd| {
+                    let node_id = nd.attribute("ref")?.parse::<i64>().ok()? as u64;
+                    Some((node_id, *node_coordinates.get(&node_id)?))
+                })
+                .collect();
+            if nodes
+                .iter()
+                .any(|node| in_bounding_box(node.1, bounding_box))
+            {
+                json_elements.push(way_element(id, &nodes, &tags));
+            }
+        }
+    }
+    Ok(json!({ "elements": json_elements }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::profile::Profile;
+    use crate::source::OsmXmlSource;
+    use crate::{get_poi, ReachableAmenity};
+
+    // A footway north from node 1 joining a residential street with
+    // sidewalks. Along it are a pharmacy and a school, with a bench too far
+    // away and a cafe JOSM has marked deleted.
+    const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/edinburgh.osm");
+
+    #[test]
+    fn finds_amenities_along_the_ways() {
+        let source = OsmXmlSource::new(PathBuf::from(FIXTURE));
+        let profile = Profile::from_name("adult").unwrap();
+        let (amenities, _) =
+            get_poi(&source, (55.95, -3.19), 600.0, profile, false, false, None).unwrap();
+        let mut names: Vec<String> = amenities
+            .iter()
+            .filter_map(|amenity: &ReachableAmenity| amenity.node.name.clone())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["\"Boots\"", "\"Primary\""]);
+    }
+}
@@ -0,0 +1,488 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use flate2::read::ZlibDecoder;
+use serde_json::{json, Value};
+
+use crate::error::{Error, Result};
+use crate::{in_bounding_box, is_selected_element, node_element, way_element, BoundingBox};
+
+// Largest BlobHeader and Blob the PBF specification allows, so a corrupt
+// length can't ask for gigabytes
+const MAX_HEADER_SIZE: usize = 64 * 1024;
+const MAX_BLOB_SIZE: usize = 32 * 1024 * 1024;
+
+// Reader for the subset of the protobuf wire format used by the OSM PBF
+// specification (https://wiki.openstreetmap.org/wiki/PBF_Format).
+struct ProtoReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+enum Field<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+impl<'a> ProtoReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        ProtoReader { data, position: 0 }
+    }
+
+    fn read_varint(&mut self) -> Option<u64> {
+        let mut result: u64 = 0;
+        let mut shift = 0;
+        loop {
+            let byte = *self.data.get(self.position)?;
+            self.position += 1;
+            result |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Some(result);
+            }
+            shift += 7;
+            if shift >= 64 {
+                return None;
+            }
+        }
+    }
+
+    fn next_field(&mut self) -> Option<(u64, Field<'a>)> {
+        if self.position >= self.data.len() {
+            return None;
+        }
+        let key = self.read_varint()?;
+        let field = match key & 0x7 {
+            0 => Field::Varint(self.read_varint()?),
+            1 => {
+                self.position += 8;
+                Field::Fixed
+            }
+            2 => {
+                let length = self.read_varint()? as usize;
+                let end = self.position.checked_add(length)?;
+                let bytes = self.data.get(self.position..end)?;
+                self.position = end;
+                Field::Bytes(bytes)
+            }
+            5 => {
+                self.position += 4;
+                Field::Fixed
+            }
+            _ => return None,
+        };
+        Some((key >> 3, field))
+    }
+}
+
+fn zigzag(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+fn packed_varints(bytes: &[u8]) -> Vec<u64> {
+    let mut reader = ProtoReader::new(bytes);
+    let mut values = Vec::new();
+    while reader.position < bytes.len() {
+        match reader.read_varint() {
+            Some(value) => values.push(value),
+            None => break,
+        }
+    }
+    values
+}
+
+fn packed_deltas(bytes: &[u8]) -> Vec<i64> {
+    let mut last = 0;
+    packed_varints(bytes)
+        .into_iter()
+        .map(|value| {
+            last += zigzag(value);
+            last
+        })
+        .collect()
+}
+
+// The file is read twice, first for the selected ways and then for the nodes,
+// as ways only reference their nodes by id and the nodes usually come first
+#[derive(Clone, Copy, PartialEq)]
+enum Pass {
+    Ways,
+    Nodes,
+}
+
+// Everything of interest found while scanning the file. Coordinates are only
+// kept for nodes inside the bounding box or on a selected way, so memory
+// follows the area searched rather than the size of the extract.
+struct PbfContents {
+    bounding_box: BoundingBox,
+    pass: Pass,
+    way_nodes: HashSet<i64>,
+    node_coordinates: HashMap<i64, (f64, f64)>,
+    tagged_nodes: Vec<(i64, HashMap<String, String>)>,
+    ways: Vec<(i64, Vec<i64>, HashMap<String, String>)>,
+}
+
+impl PbfContents {
+    fn new(bounding_box: BoundingBox) -> Self {
+        PbfContents {
+            bounding_box,
+            pass: Pass::Ways,
+            way_nodes: HashSet::new(),
+            node_coordinates: HashMap::new(),
+            tagged_nodes: Vec::new(),
+            ways: Vec::new(),
+        }
+    }
+
+    fn keep_node(
+        &mut self,
+        id: i64,
+        coordinates: (f64, f64),
+        tags: impl FnOnce() -> HashMap<String, String>,
+    ) {
+        let inside = in_bounding_box(coordinates, self.bounding_box);
+        if inside || self.way_nodes.contains(&id) {
+            self.node_coordinates.insert(id, coordinates);
+        }
+        if inside {
+            let tags = tags();
+            if !tags.is_empty() {
+                self.tagged_nodes.push((id, tags));
+            }
+        }
+    }
+}
+
+struct PrimitiveBlock<'a> {
+    strings: Vec<String>,
+    groups: Vec<&'a [u8]>,
+    granularity: i64,
+    lat_offset: i64,
+    lon_offset: i64,
+}
+
+impl PrimitiveBlock<'_> {
+    fn coordinates(&self, lat: i64, lon: i64) -> (f64, f64) {
+        (
+            1e-9 * (self.lat_offset + self.granularity * lat) as f64,
+            1e-9 * (self.lon_offset + self.granularity * lon) as f64,
+        )
+    }
+
+    fn tags(&self, keys: &[u64], values: &[u64]) -> HashMap<String, String> {
+        keys.iter()
+            .zip(values.iter())
+            .filter_map(|(key, value)| {
+                Some((
+                    self.strings.get(*key as usize)?.clone(),
+                    self.strings.get(*value as usize)?.clone(),
+                ))
+            })
+            .collect()
+    }
+}
+
+fn parse_primitive_block(data: &[u8]) -> PrimitiveBlock<'_> {
+    let mut block = PrimitiveBlock {
+        strings: Vec::new(),
+        groups: Vec::new(),
+        granularity: 100,
+        lat_offset: 0,
+        lon_offset: 0,
+    };
+    let mut reader = ProtoReader::new(data);
+    while let Some((number, field)) = reader.next_field() {
+        match (number, field) {
+            (1, Field::Bytes(bytes)) => {
+                let mut string_reader = ProtoReader::new(bytes);
+                while let Some((string_number, string_field)) = string_reader.next_field() {
+                    if let (1, Field::Bytes(string)) = (string_number, string_field) {
+                        block
+                            .strings
+                            .push(String::from_utf8_lossy(string).into_owned());
+                    }
+                }
+            }
+            (2, Field::Bytes(bytes)) => block.groups.push(bytes),
+            (17, Field::Varint(value)) => block.granularity = value as i64,
+            (19, Field::Varint(value)) => block.lat_offset = value as i64,
+            (20, Field::Varint(value)) => block.lon_offset = value as i64,
+            _ => {}
+        }
+    }
+    block
+}
+
+fn read_node(block: &PrimitiveBlock, data: &[u8], contents: &mut PbfContents) {
+    let mut id = 0;
+    let mut keys = Vec::new();
+    let mut values = Vec::new();
+    let mut lat = 0;
+    let mut lon = 0;
+    let mut reader = ProtoReader::new(data);
+    while let Some((number, field)) = reader.next_field() {
+        match (number, field) {
+            (1, Field::Varint(value)) => id = zigzag(value),
+            (2, Field::Bytes(bytes)) => keys = packed_varints(bytes),
+            (3, Field::Bytes(bytes)) => values = packed_varints(bytes),
+            (8, Field::Varint(value)) => lat = zigzag(value),
+            (9, Field::Varint(value)) => lon = zigzag(value),
+            _ => {}
+        }
+    }
+    contents.keep_node(id, block.coordinates(lat, lon), || {
+        block.tags(&keys, &values)
+    });
+}
+
+fn read_dense_nodes(block: &PrimitiveBlock, data: &[u8], contents: &mut PbfContents) -> Result<()> {
+    let mut ids = Vec::new();
+    let mut lats = Vec::new();
+    let mut lons = Vec::new();
+    let mut keys_values = Vec::new();
+    let mut reader = ProtoReader::new(data);
+    while let Some((number, field)) = reader.next_field() {
+        match (number, field) {
+            (1, Field::Bytes(bytes)) => ids = packed_deltas(bytes),
+            (8, Field::Bytes(bytes)) => lats = packed_deltas(bytes),
+            (9, Field::Bytes(bytes)) => lons = packed_deltas(bytes),
+            (10, Field::Bytes(bytes)) => keys_values = packed_varints(bytes),
+            _ => {}
+        }
+    }
+    // keys_vals holds (key, value) string indices per node, each node's list
+    // terminated by a 0
+    let mut keys_values = keys_values.into_iter();
+    for (index, id) in ids.iter().enumerate() {
+        let (Some(lat), Some(lon)) = (lats.get(index), lons.get(index)) else {
+            return Err(Error::InvalidMapFile(
+                "a dense node block has fewer coordinates than ids".to_string(),
+            ));
+        };
+        let mut keys = Vec::new();
+        let mut values = Vec::new();
+        while let Some(key) = keys_values.next() {
+            if key == 0 {
+                break;
+            }
+            keys.push(key);
+            values.push(keys_values.next().unwrap_or(0));
+        }
+        contents.keep_node(*id, block.coordinates(*lat, *lon), || {
+            block.tags(&keys, &values)
+        });
+    }
+    Ok(())
+}
+
+fn read_way(block: &PrimitiveBlock, data: &[u8], contents: &mut PbfContents) {
+    let mut id = 0;
+    let mut keys = Vec::new();
+    let mut values = Vec::new();
+    let mut refs = Vec::new();
+    let mut reader = ProtoReader::new(data);
+    while let Some((number, field)) = reader.next_field() {
+        match (number, field) {
+            (1, Field::Varint(value)) => id = value as i64,
+            (2, Field::Bytes(bytes)) => keys = packed_varints(bytes),
+            (3, Field::Bytes(bytes)) => values = packed_varints(bytes),
+            (8, Field::Bytes(bytes)) => refs = packed_deltas(bytes),
+            _ => {}
+        }
+    }
+    let tags = block.tags(&keys, &values);
+    if is_selected_element("way", &tags) {
+        contents.way_nodes.extend(refs.iter().copied());
+        contents.ways.push((id, refs, tags));
+    }
+}
+
+fn read_primitive_block(data: &[u8], contents: &mut PbfContents) -> Result<()> {
+    let block = parse_primitive_block(data);
+    for group in block.groups.iter() {
+        let mut reader = ProtoReader::new(group);
+        while let Some((number, field)) = reader.next_field() {
+            match (contents.pass, number, field) {
+                (Pass::Nodes, 1, Field::Bytes(bytes)) => read_node(&block, bytes, contents),
+                (Pass::Nodes, 2, Field::Bytes(bytes)) => read_dense_nodes(&block, bytes, contents)?,
+                (Pass::Ways, 3, Field::Bytes(bytes)) => read_way(&block, bytes, contents),
+                // relations and changesets are never selected
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+fn decompress_blob(data: &[u8]) -> Result<Vec<u8>> {
+    let mut reader = ProtoReader::new(data);
+    while let Some((number, field)) = reader.next_field() {
+        match (number, field) {
+            (1, Field::Bytes(raw)) => return Ok(raw.to_vec()),
+            (3, Field::Bytes(zlib_data)) => {
+                let mut decompressed = Vec::new();
+                ZlibDecoder::new(zlib_data)
+                    .take(MAX_BLOB_SIZE as u64 + 1)
+                    .read_to_end(&mut decompressed)
+                    .map_err(|error| Error::InvalidMapFile(error.to_string()))?;
+                if decompressed.len() > MAX_BLOB_SIZE {
+                    return Err(Error::InvalidMapFile(
+                        "a PBF blob decompresses to more than the 32 MiB allowed".to_string(),
+                    ));
+                }
+                return Ok(decompressed);
+            }
+            (4..=7, Field::Bytes(_)) => {
+                return Err(Error::InvalidMapFile(
+                    "unsupported PBF blob compression, only raw and zlib are supported".to_string(),
+                ))
+            }
+            _ => {}
+        }
+    }
+    Ok(Vec::new())
+}
+
+fn read_pbf_blocks(path: &Path, contents: &mut PbfContents) -> Result<()> {
+    let truncated = || Error::InvalidMapFile(format!("{} is truncated", path.display()));
+    let too_large = || {
+        Error::InvalidMapFile(format!(
+            "{} has a block larger than the PBF format allows",
+            path.display()
+        ))
+    };
+    let file = File::open(path)
+        .map_err(|error| Error::InvalidMapFile(format!("{}: {}", path.display(), error)))?;
+    let mut file = BufReader::new(file);
+    let mut length_bytes = [0u8; 4];
+    while file.read_exact(&mut length_bytes).is_ok() {
+        let header_size = u32::from_be_bytes(length_bytes) as usize;
+        if header_size > MAX_HEADER_SIZE {
+            return Err(too_large());
+        }
+        let mut header = vec![0u8; header_size];
+        file.read_exact(&mut header).map_err(|_| truncated())?;
+        let mut blob_type = String::new();
+        let mut blob_size = 0;
+        let mut reader = ProtoReader::new(&header);
+        while let Some((number, field)) = reader.next_field() {
+            match (number, field) {
+                (1, Field::Bytes(bytes)) => blob_type = String::from_utf8_lossy(bytes).into_owned(),
+                (3, Field::Varint(value)) => blob_size = value as usize,
+                _ => {}
+            }
+        }
+        if blob_size > MAX_BLOB_SIZE {
+            return Err(too_large());
+        }
+        let mut blob = vec![0u8; blob_size];
+        file.read_exact(&mut blob).map_err(|_| truncated())?;
+        if blob_type == "OSMData" {
+            read_primitive_block(&decompress_blob(&blob)?, contents)?;
+        }
+    }
+    Ok(())
+}
+
+fn read_pbf_contents(path: &Path, bounding_box: BoundingBox) -> Result<PbfContents> {
+    let mut contents = PbfContents::new(bounding_box);
+    read_pbf_blocks(path, &mut contents)?;
+    contents.pass = Pass::Nodes;
+    read_pbf_blocks(path, &mut contents)?;
+    Ok(contents)
+}
+
+// Reads a local .osm.pbf extract and returns the elements inside the bounding
+// box in the same shape as an Overpass `out geom` response, so it can be fed
+// straight into `response_to_structures`.
+pub fn get_pbf_json_response(path: &Path, bounding_box: BoundingBox) -> Result<Value> {
+    let contents = read_pbf_contents(path, bounding_box)?;
+    let mut elements: Vec<Value> = Vec::new();
+    // tagged nodes are only kept from inside the bounding box
+    for (id, tags) in contents.tagged_nodes.iter() {
+        if let Some(coordinates) = contents.node_coordinates.get(id) {
+            if is_selected_element("node", tags) {
+                elements.push(node_element(*id as u64, *coordinates, tags));
+            }
+        }
+    }
+    for (id, refs, tags) in contents.ways.iter() {
+        // extracts are clipped, so ways on the edge can reference missing nodes
+        let nodes: Vec<(u64, (f64, f64))> = refs
+            .iter()
+            .filter_map(|node_id| Some((*node_id as u64, *contents.node_coordinates.get(node_id)?)))
+            .collect();
+        if nodes
+            .iter()
+            .any(|node| in_bounding_box(node.1, bounding_box))
+        {
+            elements.push(way_element(*id as u64, &nodes, tags));
+        }
+    }
+    Ok(json!({ "elements": elements }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A footway of nodes 1 to 4 running north out of the box below to node 4,
+    // a pharmacy and a school inside it, a bench outside it and node 13, far
+    // away and on no way
+    const FIXTURE: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/edinburgh.osm.pbf"
+    );
+    const BOUNDING_BOX: BoundingBox = (55.94, -3.2, 55.96, -3.18);
+
+    #[test]
+    fn reads_selected_elements_inside_the_box() {
+        let response = get_pbf_json_response(Path::new(FIXTURE), BOUNDING_BOX).unwrap();
+        let elements = response["elements"].as_array().unwrap();
+        let nodes: Vec<u64> = elements
+            .iter()
+            .filter(|element| element["type"] == "node")
+            .map(|element| element["id"].as_u64().unwrap())
+            .collect();
+        assert_eq!(nodes, vec![10, 11]);
+        let ways: Vec<&Value> = elements
+            .iter()
+            .filter(|element| element["type"] == "way")
+            .collect();
+        assert_eq!(ways.len(), 1);
+        assert_eq!(ways[0]["nodes"], json!([1, 2, 3, 4]));
+        assert_eq!(ways[0]["tags"]["highway"], "footway");
+    }
+
+    #[test]
+    fn keeps_only_nodes_inside_the_box_or_on_selected_ways() {
+        let contents = read_pbf_contents(Path::new(FIXTURE), BOUNDING_BOX).unwrap();
+        let mut ids: Vec<i64> = contents.node_coordinates.keys().copied().collect();
+        ids.sort();
+        assert_eq!(ids, vec![1, 2, 3, 4, 10, 11]);
+    }
+
+    #[test]
+    fn dense_nodes_missing_coordinates_are_invalid() {
+        // ids 1 and 2, but only one latitude and longitude
+        let dense = [0x0a, 2, 2, 2, 0x42, 1, 2, 0x4a, 1, 2];
+        let block = parse_primitive_block(&[]);
+        let mut contents = PbfContents::new(BOUNDING_BOX);
+        contents.pass = Pass::Nodes;
+        assert!(matches!(
+            read_dense_nodes(&block, &dense, &mut contents),
+            Err(Error::InvalidMapFile(_))
+        ));
+    }
+
+    #[test]
+    fn oversized_blob_header_is_invalid() {
+        let path = std::env::temp_dir().join("oversized_header.osm.pbf");
+        std::fs::write(&path, [0xff, 0xff, 0xff, 0xff]).unwrap();
+        let result = read_pbf_contents(&path, BOUNDING_BOX);
+        let _ = std::fs::remove_file(&path);
+        assert!(matches!(result, Err(Error::InvalidMapFile(_))));
+    }
+}
@@ -0,0 +1,447 @@
+use std::collections::{BTreeMap, HashMap};
+
+// Speed of someone pushing a bike where cycling isn't allowed, in m/s
+const DISMOUNT_SPEED: f64 = 1.4;
+
+// Steepest incline in percent a wheelchair user can manage unassisted, and the
+// point above which it slows them down
+const MAX_INCLINE: f64 = 8.0;
+const GENTLE_INCLINE: f64 = 5.0;
+
+// Tallest kerb in metres that can still be rolled over, and the seconds lost
+// getting over one that isn't lowered
+const MAX_KERB_HEIGHT: f64 = 0.03;
+const KERB_PENALTY: f64 = 20.0;
+
+const CYCLEABLE_HIGHWAYS: [&str; 9] = [
+    "cycleway",
+    "residential",
+    "living_street",
+    "unclassified",
+    "tertiary",
+    "secondary",
+    "primary",
+    "track",
+    "path",
+];
+
+// The profile searches use unless told otherwise, and the one every cache
+// has a routing graph prepared for
+pub const DEFAULT_PROFILE: &str = "adult";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Walking,
+    Cycling,
+    Wheelchair,
+}
+
+pub const ALL_MODES: [Mode; 3] = [Mode::Walking, Mode::Cycling, Mode::Wheelchair];
+
+impl Mode {
+    // Overpass selectors for the highways, and any nodes along them, this mode
+    // needs, each still missing its bounding box
+    pub fn overpass_filters(&self) -> Vec<String> {
+        match self {
+            Mode::Walking => vec![
+                "way[highway][highway!=service][highway=footway][access!=private][type!=relation][type!=multipolygon]".to_string(),
+                "way[highway][highway!=service][sidewalk][access!=private][type!=relation][type!=multipolygon]".to_string(),
+            ],
+            Mode::Cycling => vec![
+                format!(
+                    "way[highway~\"^({})$\"][access!=private][bicycle!=no][type!=relation][type!=multipolygon]",
+                    CYCLEABLE_HIGHWAYS.join("|")
+                ),
+                "way[highway~\"^(footway|pedestrian)$\"][bicycle~\"^(yes|designated|dismount)$\"][access!=private][type!=relation][type!=multipolygon]".to_string(),
+            ],
+            Mode::Wheelchair => {
+                let mut filters = Mode::Walking.overpass_filters();
+                filters.push("node[kerb]".to_string());
+                filters
+            }
+        }
+    }
+}
+
+pub fn is_walkable_highway(tags: &HashMap<String, String>) -> bool {
+    match tags.get("highway") {
+        Some(highway) if highway != "service" => {
+            (highway == "footway" || tags.contains_key("sidewalk"))
+                && tags.get("access").map(|access| access.as_str()) != Some("private")
+        }
+        _ => false,
+    }
+}
+
+pub fn is_cycleable_highway(tags: &HashMap<String, String>) -> bool {
+    let bicycle = tags.get("bicycle").map(|bicycle| bicycle.as_str());
+    if bicycle == Some("no") || tags.get("access").map(|access| access.as_str()) == Some("private")
+    {
+        return false;
+    }
+    match tags.get("highway").map(|highway| highway.as_str()) {
+        Some("footway") | Some("pedestrian") => {
+            matches!(bicycle, Some("yes") | Some("designated") | Some("dismount"))
+        }
+        Some(highway) => CYCLEABLE_HIGHWAYS.contains(&highway),
+        None => false,
+    }
+}
+
+// Slope of an incline tag in percent, ignoring direction. Bare "up" and
+// "down" give no figure.
+fn parse_incline(incline: &str) -> Option<f64> {
+    let incline = incline.trim();
+    if let Some(percent) = incline.strip_suffix('%') {
+        return percent.trim().parse::<f64>().ok().map(f64::abs);
+    }
+    if let Some(degrees) = incline.strip_suffix('°') {
+        let degrees = degrees.trim().parse::<f64>().ok()?;
+        return Some(degrees.to_radians().tan().abs() * 100.0);
+    }
+    incline.parse::<f64>().ok().map(f64::abs)
+}
+
+// Height of a kerb:height tag in metres, which is given in metres unless a
+// unit says otherwise
+fn parse_kerb_height(height: &str) -> Option<f64> {
+    let height = height.trim();
+    if let Some(centimetres) = height.strip_suffix("cm") {
+        return centimetres
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .map(|height| height / 100.0);
+    }
+    if let Some(millimetres) = height.strip_suffix("mm") {
+        return millimetres
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .map(|height| height / 1000.0);
+    }
+    height.trim_end_matches('m').trim().parse::<f64>().ok()
+}
+
+// Share of the usual speed a wheelchair user keeps along the way, or None if
+// the way can't be used at all
+fn wheelchair_factor(tags: &HashMap<String, String>) -> Option<f64> {
+    if tags.get("highway").map(|highway| highway.as_str()) == Some("steps") {
+        return None;
+    }
+    match tags.get("wheelchair").map(|wheelchair| wheelchair.as_str()) {
+        Some("no") => return None,
+        Some("yes") | Some("designated") => return Some(1.0),
+        _ => {}
+    }
+    let mut factor = match tags.get("surface").map(|surface| surface.as_str()) {
+        Some("unhewn_cobblestone") | Some("sand") | Some("mud") | Some("grass") => return None,
+        Some("gravel") | Some("sett") | Some("cobblestone") | Some("pebblestone") => 0.5,
+        _ => 1.0,
+    };
+    if let Some(incline) = tags.get("incline") {
+        factor *= match parse_incline(incline) {
+            Some(slope) if slope > MAX_INCLINE => return None,
+            Some(slope) if slope > GENTLE_INCLINE => 0.6,
+            Some(_) => 1.0,
+            None if incline == "no" => 1.0,
+            None => 0.8,
+        };
+    }
+    if tags.get("wheelchair").map(|wheelchair| wheelchair.as_str()) == Some("limited") {
+        factor *= 0.7;
+    }
+    Some(factor)
+}
+
+// How fast someone travels along the network. Edge weights in the graph are
+// travel times, so every cost, including later penalties, shares one unit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Profile {
+    pub mode: Mode,
+    // metres per second
+    pub speed: f64,
+}
+
+impl Profile {
+    // Accepts one of the named profiles, optionally followed by a custom speed
+    // in m/s such as "cycling:5.5", or a bare speed for walking
+    pub fn from_name(name: &str) -> Option<Profile> {
+        let (name, custom_speed) = match name.split_once(':') {
+            Some((name, speed)) => (name, Some(speed.parse::<f64>().ok()?)),
+            None => (name, None),
+        };
+        let (mode, speed) = match name {
+            "adult" => (Mode::Walking, 1.4),
+            "elderly" => (Mode::Walking, 1.0),
+            "child" => (Mode::Walking, 1.1),
+            "cycling" => (Mode::Cycling, 4.2),
+            "wheelchair" => (Mode::Wheelchair, 1.0),
+            custom => (Mode::Walking, custom.parse::<f64>().ok()?),
+        };
+        let speed = custom_speed.unwrap_or(speed);
+        if !speed.is_finite() || speed <= 0.0 {
+            return None;
+        }
+        Some(Profile { mode, speed })
+    }
+
+    // The speed along a way in m/s, or None if the profile can't use it at
+    // all. Ways from caches written before tags were kept carry no tags and
+    // are assumed walkable.
+    pub fn way_speed(&self, tags: &BTreeMap<String, String>) -> Option<f64> {
+        let tags: HashMap<String, String> = tags.clone().into_iter().collect();
+        match self.mode {
+            Mode::Walking => {
+                if tags.is_empty() || is_walkable_highway(&tags) {
+                    Some(self.speed)
+                } else {
+                    None
+                }
+            }
+            Mode::Cycling => {
+                if !is_cycleable_highway(&tags) {
+                    None
+                } else if tags.get("bicycle").map(|bicycle| bicycle.as_str()) == Some("dismount") {
+                    Some(DISMOUNT_SPEED.min(self.speed))
+                } else {
+                    Some(self.speed)
+                }
+            }
+            Mode::Wheelchair => {
+                if tags.is_empty() {
+                    Some(self.speed)
+                } else if is_walkable_highway(&tags) {
+                    wheelchair_factor(&tags).map(|factor| self.speed * factor)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    // Seconds lost passing through a node along a way, or None if the profile
+    // can't pass it at all
+    pub fn node_penalty(&self, tags: &BTreeMap<String, String>) -> Option<f64> {
+        if self.mode != Mode::Wheelchair {
+            return Some(0.0);
+        }
+        if tags.get("wheelchair").map(|wheelchair| wheelchair.as_str()) == Some("no") {
+            return None;
+        }
+        if let Some(height) = tags
+            .get("kerb:height")
+            .and_then(|height| parse_kerb_height(height))
+        {
+            return if height > MAX_KERB_HEIGHT {
+                None
+            } else if height > 0.0 {
+                Some(KERB_PENALTY)
+            } else {
+                Some(0.0)
+            };
+        }
+        match tags.get("kerb").map(|kerb| kerb.as_str()) {
+            Some("raised") => None,
+            Some("regular") | Some("rolled") | Some("yes") => Some(KERB_PENALTY),
+            _ => Some(0.0),
+        }
+    }
+
+    // Whether the profile can get into an amenity with these tags
+    pub fn can_enter(&self, tags: &BTreeMap<String, String>) -> bool {
+        self.mode != Mode::Wheelchair
+            || tags.get("wheelchair").map(|wheelchair| wheelchair.as_str()) != Some("no")
+    }
+
+    // Seconds needed to cover the distance in metres
+    pub fn travel_time(&self, metres: f64) -> f64 {
+        metres / self.speed
+    }
+
+    // Metres covered in the time in seconds
+    pub fn travel_distance(&self, seconds: f64) -> f64 {
+        seconds * self.speed
+    }
+}
+
+// Share of the flat speed kept on a slope, rise over run, following Tobler's
+// hiking function. The fastest slope is slightly downhill, so climbing and
+// descending the same edge cost different amounts.
+pub fn slope_factor(slope: f64) -> f64 {
+    (-3.5 * (slope + 0.05).abs()).exp() / (-3.5_f64 * 0.05).exp()
+}
+
+// Edge weights are whole milliseconds. fast_paths only takes integer weights,
+// and whole seconds would round away most of the short segments footways are
+// made of.
+pub fn seconds_to_weight(seconds: f64) -> usize {
+    (seconds * 1000.0).round() as usize
+}
+
+pub fn weight_to_seconds(weight: usize) -> f64 {
+    weight as f64 / 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::source::OsmXmlSource;
+    use crate::{get_poi, ReachableAmenity};
+
+    fn tags(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn node_tags(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        tags(pairs).into_iter().collect()
+    }
+
+    #[test]
+    fn rejects_speeds_that_are_not_positive_and_finite() {
+        for name in ["0", "-1.4", "nan", "inf", "cycling:inf", "wheelchair:NaN"] {
+            assert!(Profile::from_name(name).is_none(), "{}", name);
+        }
+        assert_eq!(Profile::from_name("cycling:5.5").unwrap().speed, 5.5);
+    }
+
+    #[test]
+    fn kerbs_only_slow_wheelchairs() {
+        let kerb = node_tags(&[("kerb", "raised")]);
+        assert_eq!(
+            Profile::from_name("adult").unwrap().node_penalty(&kerb),
+            Some(0.0)
+        );
+        assert_eq!(
+            Profile::from_name("wheelchair")
+                .unwrap()
+                .node_penalty(&kerb),
+            None
+        );
+    }
+
+    #[test]
+    fn kerb_types() {
+        let wheelchair = Profile::from_name("wheelchair").unwrap();
+        let cases = [
+            ("raised", None),
+            ("regular", Some(KERB_PENALTY)),
+            ("rolled", Some(KERB_PENALTY)),
+            ("lowered", Some(0.0)),
+            ("flush", Some(0.0)),
+        ];
+        for (kerb, penalty) in cases {
+            assert_eq!(
+                wheelchair.node_penalty(&node_tags(&[("kerb", kerb)])),
+                penalty,
+                "{}",
+                kerb
+            );
+        }
+        assert_eq!(
+            wheelchair.node_penalty(&node_tags(&[("wheelchair", "no")])),
+            None
+        );
+    }
+
+    #[test]
+    fn kerb_heights_take_units_and_default_to_metres() {
+        let wheelchair = Profile::from_name("wheelchair").unwrap();
+        let cases = [
+            ("0.02", Some(KERB_PENALTY)),
+            ("0.02 m", Some(KERB_PENALTY)),
+            ("2cm", Some(KERB_PENALTY)),
+            ("20 mm", Some(KERB_PENALTY)),
+            ("5cm", None),
+            ("0.1", None),
+            ("0", Some(0.0)),
+        ];
+        for (height, penalty) in cases {
+            // the height wins over the kerb type
+            let tags = node_tags(&[("kerb", "raised"), ("kerb:height", height)]);
+            assert_eq!(wheelchair.node_penalty(&tags), penalty, "{}", height);
+        }
+    }
+
+    #[test]
+    fn wheelchairs_avoid_steep_inclines() {
+        let cases = [
+            ("10%", None),
+            ("-10%", None),
+            ("6%", Some(0.6)),
+            ("3%", Some(1.0)),
+            ("5°", None),
+            ("2°", Some(1.0)),
+            ("up", Some(0.8)),
+            ("no", Some(1.0)),
+        ];
+        for (incline, factor) in cases {
+            let tags = tags(&[("highway", "footway"), ("incline", incline)]);
+            assert_eq!(wheelchair_factor(&tags), factor, "{}", incline);
+        }
+    }
+
+    #[test]
+    fn wheelchairs_slow_on_rough_surfaces() {
+        let cases = [
+            ("asphalt", Some(1.0)),
+            ("sett", Some(0.5)),
+            ("gravel", Some(0.5)),
+            ("grass", None),
+            ("sand", None),
+        ];
+        for (surface, factor) in cases {
+            let tags = tags(&[("highway", "footway"), ("surface", surface)]);
+            assert_eq!(wheelchair_factor(&tags), factor, "{}", surface);
+        }
+        let limited = tags(&[
+            ("highway", "footway"),
+            ("surface", "gravel"),
+            ("wheelchair", "limited"),
+        ]);
+        assert_eq!(wheelchair_factor(&limited), Some(0.5 * 0.7));
+        // an explicit yes outweighs the surface
+        let accessible = tags(&[
+            ("highway", "footway"),
+            ("surface", "gravel"),
+            ("wheelchair", "yes"),
+        ]);
+        assert_eq!(wheelchair_factor(&accessible), Some(1.0));
+        assert_eq!(wheelchair_factor(&tags(&[("highway", "steps")])), None);
+    }
+
+    // The .osm fixture with a regular kerb, node 5, on the footway north from
+    // node 1, and a school along it tagged wheelchair=no
+    const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/edinburgh.osm");
+
+    fn wheelchair_search() -> Vec<ReachableAmenity> {
+        let source = OsmXmlSource::new(PathBuf::from(FIXTURE));
+        let profile = Profile::from_name("wheelchair").unwrap();
+        let (amenities, _) =
+            get_poi(&source, (55.95, -3.19), 600.0, profile, false, false, None).unwrap();
+        amenities
+    }
+
+    #[test]
+    fn wheelchair_skips_amenities_tagged_wheelchair_no() {
+        let names: Vec<Option<String>> = wheelchair_search()
+            .into_iter()
+            .map(|amenity| amenity.node.name)
+            .collect();
+        assert_eq!(names, vec![Some("\"Boots\"".to_string())]);
+    }
+
+    #[test]
+    fn wheelchair_pays_for_the_kerb() {
+        let amenities = wheelchair_search();
+        let pharmacy = &amenities[0];
+        // 1 m/s along the route, plus the penalty for getting over the kerb
+        let kerb_time = pharmacy.walking_time - pharmacy.network_distance;
+        assert!((kerb_time - KERB_PENALTY).abs() < 0.1, "{}", kerb_time);
+    }
+}
@@ -0,0 +1,71 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+use fast_paths::InputGraph;
+
+// Weight of the shortest path from the source to every node reached within
+// the limit, and the node each one was reached from
+pub type ShortestPathTree = (HashMap<usize, usize>, HashMap<usize, usize>);
+
+// The (neighbour, weight) pairs leaving each node
+pub type Adjacency = Vec<Vec<(usize, usize)>>;
+
+pub fn adjacency(graph: &InputGraph) -> Adjacency {
+    let mut adjacency: Adjacency = vec![Vec::new(); graph.get_num_nodes()];
+    for edge in graph.get_edges().iter() {
+        adjacency[edge.from].push((edge.to, edge.weight));
+    }
+    adjacency
+}
+
+// Single source Dijkstra over the input graph that stops expanding once the
+// limit is passed, so one search labels everything reachable from the origin
+pub fn shortest_path_tree(graph: &InputGraph, source: usize, limit: usize) -> ShortestPathTree {
+    bounded_search(&adjacency(graph), source, limit)
+}
+
+// shortest_path_tree over an adjacency list built once, for running many
+// searches over the same graph
+pub fn bounded_search(adjacency: &Adjacency, source: usize, limit: usize) -> ShortestPathTree {
+    let mut distances: HashMap<usize, usize> = HashMap::new();
+    let mut predecessors: HashMap<usize, usize> = HashMap::new();
+    let mut heap: BinaryHeap<Reverse<(usize, usize)>> = BinaryHeap::new();
+    if source >= adjacency.len() {
+        return (distances, predecessors);
+    }
+    distances.insert(source, 0);
+    heap.push(Reverse((0, source)));
+    while let Some(Reverse((distance, node))) = heap.pop() {
+        if distance > distances[&node] {
+            continue;
+        }
+        for (neighbour, weight) in adjacency[node].iter() {
+            let new_distance = distance + weight;
+            if new_distance > limit {
+                continue;
+            }
+            if distances
+                .get(neighbour)
+                .is_none_or(|old_distance| new_distance < *old_distance)
+            {
+                distances.insert(*neighbour, new_distance);
+                predecessors.insert(*neighbour, node);
+                heap.push(Reverse((new_distance, *neighbour)));
+            }
+        }
+    }
+    (distances, predecessors)
+}
+
+// The nodes from the source to the target, both included, following the
+// predecessors back from the target
+pub fn path_to(predecessors: &HashMap<usize, usize>, target: usize) -> Vec<usize> {
+    let mut path = vec![target];
+    let mut node = target;
+    while let Some(previous) = predecessors.get(&node) {
+        path.push(*previous);
+        node = *previous;
+    }
+    path.reverse();
+    path
+}
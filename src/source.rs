@@ -0,0 +1,261 @@
+use std::cell::OnceCell;
+use std::path::{Path, PathBuf};
+
+use rayon::prelude::*;
+
+use crate::error::Result;
+use crate::profile::Mode;
+use crate::tiles::{load_tiles, tiles_for, TILES_ROOT};
+use crate::{
+    cull_map_data, get_active_url, get_overpass_json_response, node_in_bounding_box, osm_xml, pbf,
+    read_poi_cache, response_to_structures, structures_from_ways, way_in_bounding_box, BoundingBox,
+    MapData, Node, Way,
+};
+
+// Anything that can supply the amenities and walkable ways of an area.
+// Implementations only have to cover the bounding box they are asked for,
+// returning more than that is fine.
+pub trait MapDataSource {
+    fn get_map_data(&self, bounding_box: BoundingBox) -> Result<MapData>;
+}
+
+// Live queries against an Overpass API instance, fetching only the highways
+// the travel mode can use
+pub struct OverpassSource {
+    url: String,
+    mode: Mode,
+}
+
+impl OverpassSource {
+    pub fn new(url: String, mode: Mode) -> Self {
+        OverpassSource { url, mode }
+    }
+}
+
+impl MapDataSource for OverpassSource {
+    fn get_map_data(&self, bounding_box: BoundingBox) -> Result<MapData> {
+        response_to_structures(get_overpass_json_response(
+            bounding_box,
+            self.url.clone(),
+            false,
+            &[self.mode],
+        )?)
+    }
+}
+
+// A cache directory written by `write_poi_cache`
+pub struct CacheSource {
+    city: String,
+    radius_string: String,
+}
+
+impl CacheSource {
+    pub fn new(city: String, radius_string: String) -> Self {
+        CacheSource {
+            city,
+            radius_string,
+        }
+    }
+}
+
+impl MapDataSource for CacheSource {
+    fn get_map_data(&self, bounding_box: BoundingBox) -> Result<MapData> {
+        let (amenities, highways) = read_poi_cache(self.city.clone(), self.radius_string.clone())?;
+        Ok(cull_map_data(amenities, highways, bounding_box))
+    }
+}
+
+// Zoom 14 tiles under ./Cache/tiles, fetched from Overpass the first time
+// a query needs them, so neighbouring areas share one seamless cache
+pub struct TileSource {
+    // Only looked up once a tile actually has to be fetched
+    url: OnceCell<String>,
+    compress: bool,
+}
+
+impl TileSource {
+    pub fn new(compress: bool) -> Self {
+        TileSource {
+            url: OnceCell::new(),
+            compress,
+        }
+    }
+}
+
+impl MapDataSource for TileSource {
+    fn get_map_data(&self, bounding_box: BoundingBox) -> Result<MapData> {
+        let url = || match self.url.get() {
+            Some(url) => Ok(url.clone()),
+            None => {
+                let url = get_active_url()?;
+                let _ = self.url.set(url.clone());
+                Ok(url)
+            }
+        };
+        let (amenities, highways) = load_tiles(
+            Path::new(TILES_ROOT),
+            &tiles_for(bounding_box),
+            &url,
+            self.compress,
+        )?;
+        Ok(cull_map_data(amenities, highways, bounding_box))
+    }
+}
+
+// A local .osm.pbf extract
+pub struct PbfSource {
+    path: PathBuf,
+}
+
+impl PbfSource {
+    pub fn new(path: PathBuf) -> Self {
+        PbfSource { path }
+    }
+}
+
+impl MapDataSource for PbfSource {
+    fn get_map_data(&self, bounding_box: BoundingBox) -> Result<MapData> {
+        response_to_structures(pbf::get_pbf_json_response(&self.path, bounding_box)?)
+    }
+}
+
+// A local .osm XML file
+pub struct OsmXmlSource {
+    path: PathBuf,
+}
+
+impl OsmXmlSource {
+    pub fn new(path: PathBuf) -> Self {
+        OsmXmlSource { path }
+    }
+}
+
+impl MapDataSource for OsmXmlSource {
+    fn get_map_data(&self, bounding_box: BoundingBox) -> Result<MapData> {
+        response_to_structures(osm_xml::get_osm_xml_json_response(
+            &self.path,
+            bounding_box,
+        )?)
+    }
+}
+
+// Amenities and ways that are already loaded, so many searches can share one
+// read of a cache
+pub struct InMemorySource {
+    amenities: Vec<Node>,
+    highways: Vec<Way>,
+}
+
+impl InMemorySource {
+    pub fn new(amenities: Vec<Node>, highways: Vec<Way>) -> Self {
+        InMemorySource {
+            amenities,
+            highways,
+        }
+    }
+}
+
+// Only what falls inside the bounding box is copied out, as the same source
+// serves many searches
+impl MapDataSource for InMemorySource {
+    fn get_map_data(&self, bounding_box: BoundingBox) -> Result<MapData> {
+        let amenities: Vec<Node> = self
+            .amenities
+            .iter()
+            .filter(|amenity| node_in_bounding_box(amenity, bounding_box))
+            .cloned()
+            .collect();
+        let highways: Vec<Way> = self
+            .highways
+            .par_iter()
+            .filter(|highway| way_in_bounding_box(highway, bounding_box))
+            .cloned()
+            .collect();
+        Ok(structures_from_ways(amenities, highways))
+    }
+}
+
+// Picks the file reader from the extension, .osm.pbf or anything else as XML
+pub fn osm_file_source(path: PathBuf) -> Box<dyn MapDataSource> {
+    if path.to_string_lossy().ends_with(".pbf") {
+        Box::new(PbfSource::new(path))
+    } else {
+        Box::new(OsmXmlSource::new(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    fn node(id: usize, lat: f64, lon: f64, name: Option<&str>) -> Node {
+        Node {
+            name: name.map(|name| name.to_string()),
+            coordinate: (lat.to_bits(), lon.to_bits()),
+            id,
+            category: None,
+            tags: BTreeMap::new(),
+        }
+    }
+
+    fn way(id: usize, nodes: Vec<Node>) -> Way {
+        Way {
+            id,
+            nodes,
+            tags: BTreeMap::from([("highway".to_string(), "footway".to_string())]),
+        }
+    }
+
+    // One way crossing the northern edge of the box below and one well
+    // outside it, with an amenity on either side of the edge
+    fn source() -> InMemorySource {
+        InMemorySource::new(
+            vec![
+                node(10, 55.95, -3.19, Some("inside")),
+                node(11, 55.97, -3.19, Some("outside")),
+            ],
+            vec![
+                way(
+                    100,
+                    vec![node(1, 55.95, -3.19, None), node(2, 55.97, -3.19, None)],
+                ),
+                way(
+                    101,
+                    vec![node(3, 56.5, -3.19, None), node(4, 56.6, -3.19, None)],
+                ),
+            ],
+        )
+    }
+
+    const BOUNDING_BOX: BoundingBox = (55.94, -3.2, 55.96, -3.18);
+
+    #[test]
+    fn keeps_what_the_box_touches() {
+        let (amenities, highways, highway_nodes, nodes_lut) =
+            source().get_map_data(BOUNDING_BOX).unwrap();
+        let amenity_ids: Vec<usize> = amenities.iter().map(|amenity| amenity.id).collect();
+        assert_eq!(amenity_ids, vec![10]);
+        // ways crossing the edge are kept whole
+        assert_eq!(highways.len(), 1);
+        assert_eq!(highways[0].id, 100);
+        let mut node_ids: Vec<usize> = highway_nodes.keys().copied().collect();
+        node_ids.sort();
+        assert_eq!(node_ids, vec![1, 2]);
+        for id in [1, 2, 10] {
+            assert!(nodes_lut.contains_right(&id));
+        }
+        assert_eq!(nodes_lut.len(), 3);
+    }
+
+    #[test]
+    fn serves_many_searches() {
+        let source = source();
+        let (amenities, highways, _, _) = source.get_map_data(BOUNDING_BOX).unwrap();
+        assert_eq!((amenities.len(), highways.len()), (1, 1));
+        let (amenities, highways, _, _) = source.get_map_data((56.4, -3.2, 56.7, -3.18)).unwrap();
+        assert_eq!((amenities.len(), highways.len()), (0, 1));
+        assert_eq!(highways[0].id, 101);
+    }
+}
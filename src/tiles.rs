@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use crate::cache::{read_binary_cache, write_binary_cache};
+use crate::error::{Error, Result};
+use crate::geocode::response_to_addresses;
+use crate::profile::ALL_MODES;
+use crate::{get_overpass_json_response, response_to_structures, BoundingBox, MapData, Node, Way};
+
+// Zoom 14 tiles are about 2.4 km wide at the equator, shrinking towards the
+// poles, so a 15 minute walk touches a handful of them
+pub const TILE_ZOOM: u32 = 14;
+
+pub const TILES_ROOT: &str = "./Cache/tiles";
+
+pub type Tile = (u32, u32);
+
+// The slippy map tile containing the point
+pub fn tile_for(coordinates: (f64, f64)) -> Tile {
+    let tiles = 2_u32.pow(TILE_ZOOM) as f64;
+    let lat = coordinates.0.to_radians();
+    let x = ((coordinates.1 + 180.0) / 360.0 * tiles).floor();
+    let y = ((1.0 - lat.tan().asinh() / PI) / 2.0 * tiles).floor();
+    (
+        x.clamp(0.0, tiles - 1.0) as u32,
+        y.clamp(0.0, tiles - 1.0) as u32,
+    )
+}
+
+// (south, west, north, east) of the tile
+pub fn tile_bounding_box((x, y): Tile) -> BoundingBox {
+    let tiles = 2_u32.pow(TILE_ZOOM) as f64;
+    let lon = |x: f64| x / tiles * 360.0 - 180.0;
+    let lat = |y: f64| (PI * (1.0 - 2.0 * y / tiles)).sinh().atan().to_degrees();
+    (
+        lat(y as f64 + 1.0),
+        lon(x as f64),
+        lat(y as f64),
+        lon(x as f64 + 1.0),
+    )
+}
+
+// Every tile the bounding box touches
+pub fn tiles_for(bounding_box: BoundingBox) -> Vec<Tile> {
+    // tile rows count down from the north
+    let (west, north) = tile_for((bounding_box.2, bounding_box.1));
+    let (east, south) = tile_for((bounding_box.0, bounding_box.3));
+    (west..=east)
+        .flat_map(|x| (north..=south).map(move |y| (x, y)))
+        .collect()
+}
+
+fn tile_directory(root: &Path, (x, y): Tile) -> PathBuf {
+    root.join(TILE_ZOOM.to_string())
+        .join(format!("{}_{}", x, y))
+}
+
+// Fetches the tile from Overpass and stores it. Ways crossing the tile edge
+// come back whole, so each tile holds complete ways.
+fn fetch_tile(root: &Path, tile: Tile, url: String, compress: bool) -> Result<()> {
+    println!("Fetching tile {}/{}/{}", TILE_ZOOM, tile.0, tile.1);
+    let response: Value =
+        get_overpass_json_response(tile_bounding_box(tile), url, true, &ALL_MODES)?;
+    let addresses = response_to_addresses(&response);
+    let (amenities, highways, _, _): MapData = response_to_structures(response)?;
+    let directory = tile_directory(root, tile);
+    fs::create_dir_all(&directory)?;
+    write_binary_cache(&directory, &amenities, &highways, &addresses, compress)
+}
+
+// Loads the tiles, fetching the ones not cached yet, and merges them. Ways
+// and amenities found in several tiles are kept once, joining up the nodes
+// each tile knows about so ways carry on seamlessly across tile edges.
+pub fn load_tiles(
+    root: &Path,
+    tiles: &[Tile],
+    url: &dyn Fn() -> Result<String>,
+    compress: bool,
+) -> Result<(Vec<Node>, Vec<Way>)> {
+    let mut amenities: HashMap<usize, Node> = HashMap::new();
+    let mut way_nodes: HashMap<usize, Node> = HashMap::new();
+    let mut ways: HashMap<usize, Way> = HashMap::new();
+    for tile in tiles.iter() {
+        let directory = tile_directory(root, *tile);
+        // a tile that doesn't read back even after fetching it again would
+        // leave a hole in the map, so the search stops instead
+        let (tile_amenities, tile_highways) = match read_binary_cache(&directory) {
+            Some(loaded) => loaded,
+            None => {
+                fetch_tile(root, *tile, url()?, compress)?;
+                read_binary_cache(&directory)
+                    .ok_or_else(|| Error::CacheCorrupt(directory.display().to_string()))?
+            }
+        };
+        for amenity in tile_amenities.into_iter() {
+            amenities.entry(amenity.id).or_insert(amenity);
+        }
+        for highway in tile_highways.into_iter() {
+            // kerbs are only tagged in the tile holding the kerb node
+            for node in highway.nodes.iter() {
+                let known = way_nodes.entry(node.id).or_insert_with(|| node.clone());
+                if known.tags.is_empty() {
+                    known.tags = node.tags.clone();
+                }
+            }
+            match ways.get(&highway.id) {
+                Some(known) if known.nodes.len() >= highway.nodes.len() => {}
+                _ => {
+                    ways.insert(highway.id, highway);
+                }
+            }
+        }
+    }
+    let highways: Vec<Way> = ways
+        .into_values()
+        .map(|mut highway| {
+            for node in highway.nodes.iter_mut() {
+                if let Some(merged) = way_nodes.get(&node.id) {
+                    node.tags = merged.tags.clone();
+                }
+            }
+            highway
+        })
+        .collect();
+    Ok((amenities.into_values().collect(), highways))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    #[test]
+    fn finds_the_tile_for_a_point() {
+        assert_eq!(tile_for((55.955, -3.19)), (8046, 5105));
+        // rows count down from the north, columns up from the west
+        assert_eq!(tile_for((55.962, -3.19)).1, 5104);
+        assert_eq!(tile_for((55.949, -3.19)).1, 5106);
+        assert_eq!(tile_for((55.955, -3.17)).0, 8047);
+        let (south, west, north, east) = tile_bounding_box((8046, 5105));
+        assert!(south < 55.955 && 55.955 < north);
+        assert!(west < -3.19 && -3.19 < east);
+    }
+
+    #[test]
+    fn neighbouring_tiles_share_edges() {
+        let tile = tile_bounding_box((8046, 5105));
+        let east = tile_bounding_box((8047, 5105));
+        let south = tile_bounding_box((8046, 5106));
+        assert_eq!(tile.3, east.1);
+        assert_eq!(tile.0, south.2);
+        let world = tile_bounding_box((0, 0));
+        assert_eq!(world.1, -180.0);
+        assert!((world.2 - 85.0511).abs() < 1e-4);
+    }
+
+    #[test]
+    fn covers_the_bounding_box_with_tiles() {
+        assert_eq!(
+            tiles_for((55.95, -3.191, 55.955, -3.189)),
+            vec![(8046, 5105)]
+        );
+        // a box around the south east corner of the tile
+        let (south, _, _, east) = tile_bounding_box((8046, 5105));
+        let corner = (south - 0.001, east - 0.001, south + 0.001, east + 0.001);
+        assert_eq!(
+            tiles_for(corner),
+            vec![(8046, 5105), (8046, 5106), (8047, 5105), (8047, 5106)]
+        );
+    }
+
+    fn node(id: usize, tags: &[(&str, &str)]) -> Node {
+        Node {
+            name: None,
+            coordinate: (
+                55.95_f64.to_bits(),
+                (-3.19_f64 + id as f64 * 0.01).to_bits(),
+            ),
+            id,
+            category: None,
+            tags: tags
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+
+    fn footway(nodes: Vec<Node>) -> Way {
+        Way {
+            id: 7,
+            nodes,
+            tags: BTreeMap::from([("highway".to_string(), "footway".to_string())]),
+        }
+    }
+
+    #[test]
+    fn merges_ways_across_tiles() {
+        let root = std::env::temp_dir().join("load_tiles_merge");
+        let _ = fs::remove_dir_all(&root);
+        let (west, east) = ((8046, 5105), (8047, 5105));
+        // the western tile has the whole way, but only the eastern one knows
+        // node 3 is a kerb
+        let whole = footway(vec![node(1, &[]), node(2, &[]), node(3, &[])]);
+        let part = footway(vec![node(2, &[]), node(3, &[("kerb", "lowered")])]);
+        for (tile, highway) in [(west, whole), (east, part)] {
+            let directory = tile_directory(&root, tile);
+            fs::create_dir_all(&directory).unwrap();
+            let amenities = [node(10, &[("amenity", "pharmacy")])];
+            write_binary_cache(&directory, &amenities, &[highway], &[], false).unwrap();
+        }
+        let url = || Err(Error::InvalidInput("tiles are cached".to_string()));
+        let loaded = load_tiles(&root, &[east, west], &url, false);
+        let _ = fs::remove_dir_all(&root);
+        let (amenities, highways) = loaded.unwrap();
+        assert_eq!(amenities.len(), 1);
+        assert_eq!(highways.len(), 1);
+        let ids: Vec<usize> = highways[0].nodes.iter().map(|node| node.id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+        assert_eq!(highways[0].nodes[2].tags["kerb"], "lowered");
+    }
+}